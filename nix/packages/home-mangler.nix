@@ -11,7 +11,12 @@
   craneLib = crane.mkLib pkgs;
 
   commonArgs' = {
-    src = craneLib.cleanCargoSource (craneLib.path ../../.);
+    # Keep the checked-in JSON Schema, which the tests compare against.
+    src = lib.cleanSourceWith {
+      src = craneLib.path ../../.;
+      filter = path: type:
+        (lib.hasSuffix ".schema.json" path) || (craneLib.filterCargoSources path type);
+    };
 
     nativeBuildInputs = lib.optionals stdenv.isDarwin [
       # Additional darwin specific inputs can be set here
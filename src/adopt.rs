@@ -0,0 +1,148 @@
+use std::fmt::Write;
+
+use miette::miette;
+use miette::Context;
+use miette::IntoDiagnostic;
+
+use crate::cli::AdoptArgs;
+use crate::config::Config;
+use crate::nix::ProfileListV3Element;
+use crate::packages::flake_attr;
+use crate::status::format_elements;
+
+/// Print or write a Nix snippet adding the profile's unmanaged packages to the configuration.
+pub fn adopt(config: &Config, args: &AdoptArgs) -> miette::Result<()> {
+    let nix = config.nix()?;
+    let flake = nix.resolve(config.flake()?)?;
+    let profile = nix.profile_list()?;
+    let unmanaged = profile.unmanaged_elements(&flake, &flake_attr(config.hostname()));
+
+    if unmanaged.is_empty() {
+        tracing::info!("All packages in the profile are managed by home-mangler");
+        return Ok(());
+    }
+
+    let (adopted, skipped): (Vec<_>, Vec<_>) = unmanaged
+        .into_iter()
+        .partition(|(_, element)| nixpkgs_attr(element).is_some());
+
+    let file_name = args
+        .write
+        .as_ref()
+        .and_then(|path| path.file_name())
+        .unwrap_or("adopted-packages.nix");
+    let snippet = nix_snippet(file_name, &adopted, &skipped);
+
+    match &args.write {
+        Some(path) => {
+            if path.exists() {
+                return Err(miette!(
+                    help = "Choose another path, or remove the file first",
+                    "{path} already exists"
+                ));
+            }
+            std::fs::write(path, snippet)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to write {path}"))?;
+            tracing::info!(
+                "Wrote {path}; add it to your configuration with:\n    packages = [ ... ] ++ import ./{file_name} pkgs;"
+            );
+        }
+        None => print!("{snippet}"),
+    }
+
+    if !skipped.is_empty() {
+        tracing::warn!(
+            "These packages aren't from nixpkgs, so they weren't adopted; add their flakes as inputs to your configuration instead:\n{}",
+            format_elements(&skipped)
+        );
+    }
+
+    if args.remove && !adopted.is_empty() {
+        tracing::info!(
+            "Removing adopted packages from the profile:\n{}",
+            format_elements(&adopted)
+        );
+        let names = adopted
+            .iter()
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        nix.remove_elements(&names)?;
+        tracing::info!("Run `home-mangler` after adding the snippet to reinstall them");
+    }
+
+    Ok(())
+}
+
+/// Format a Nix function from `pkgs` to a list of the adopted packages.
+fn nix_snippet(
+    file_name: &str,
+    adopted: &[(String, &ProfileListV3Element)],
+    skipped: &[(String, &ProfileListV3Element)],
+) -> String {
+    let mut ret = format!(
+        "# Packages adopted from `nix profile` by `home-mangler adopt`.\n\
+         #\n\
+         # Add these to your configuration with:\n\
+         #   packages = [ ... ] ++ import ./{file_name} pkgs;\n\
+         pkgs: [\n"
+    );
+
+    for (_, element) in adopted {
+        if let Some(attr) = nixpkgs_attr(element) {
+            let _ = writeln!(ret, "  pkgs.{}", nix_attr_path(attr));
+        }
+    }
+
+    for (_, element) in skipped {
+        let _ = writeln!(ret, "  # Not from nixpkgs: {}", element.source());
+    }
+
+    ret.push_str("]\n");
+    ret
+}
+
+/// If the element was installed from nixpkgs, its attribute path in `pkgs`, like `hello` for
+/// `legacyPackages.x86_64-linux.hello`.
+fn nixpkgs_attr(element: &ProfileListV3Element) -> Option<&str> {
+    let url = element.original_url.as_deref().or(element.url.as_deref())?;
+    let url = url.to_ascii_lowercase();
+    let is_nixpkgs = url == "flake:nixpkgs"
+        || url.starts_with("flake:nixpkgs/")
+        || url.starts_with("github:nixos/nixpkgs")
+        || url.starts_with("https://github.com/nixos/nixpkgs/");
+    if !is_nixpkgs {
+        return None;
+    }
+
+    let attr_path = element.attr_path.as_deref()?;
+    let (prefix, rest) = attr_path.split_once('.')?;
+    if prefix != "legacyPackages" && prefix != "packages" {
+        return None;
+    }
+    // Skip the system.
+    let (_system, attr) = rest.split_once('.')?;
+    Some(attr)
+}
+
+/// Quote the components of an attribute path which aren't valid Nix identifiers.
+fn nix_attr_path(attr_path: &str) -> String {
+    attr_path
+        .split('.')
+        .map(|component| {
+            let is_identifier = component
+                .chars()
+                .next()
+                .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+                && component
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '\'' | '-'));
+            if is_identifier {
+                component.to_owned()
+            } else {
+                format!("\"{component}\"")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(".")
+}
@@ -1,4 +1,16 @@
+use std::collections::BTreeSet;
+
 use camino::Utf8PathBuf;
+use clap::parser::ValueSource;
+use clap::CommandFactory;
+use clap::FromArgMatches;
+use miette::miette;
+
+use crate::config::ConfigLayer;
+use crate::config::ConfigSource;
+use crate::gc::Age;
+use crate::timings::TimingsOptions;
+use crate::tracing::LogFormat;
 
 use crate::ProjectPaths;
 
@@ -13,6 +25,10 @@ pub struct Args {
     #[arg(long, env = "HOME_MANGLER_LOG")]
     pub log_filter: Option<String>,
 
+    /// Format for log output.
+    #[arg(long, value_enum, default_value_t, env = "HOME_MANGLER_LOG_FORMAT")]
+    pub log_format: LogFormat,
+
     /// Alias for `--log-filter=trace`.
     #[arg(long)]
     pub debug: bool,
@@ -23,7 +39,8 @@ pub struct Args {
 
     /// Path to the configuration file to use.
     ///
-    /// Defaults to `~/.config/home-mangler/config.toml`.
+    /// Defaults to `~/.config/home-mangler/config.toml`. This replaces the user configuration
+    /// file; system-wide configuration in `/etc/xdg/home-mangler/config.toml` is still loaded.
     #[arg(long)]
     pub config: Option<Utf8PathBuf>,
 
@@ -45,6 +62,12 @@ pub struct Args {
     #[arg(long)]
     pub update: bool,
 
+    /// Update only the given `--flake` input before building configuration. Implies `--update`.
+    ///
+    /// May be given multiple times.
+    #[arg(long, value_name = "NAME")]
+    pub update_input: Vec<String>,
+
     /// Profile to use for `nix profile` operations.
     #[arg(long, env = "NIX_PROFILE")]
     pub profile: Option<Utf8PathBuf>,
@@ -54,9 +77,227 @@ pub struct Args {
     /// This corresponds to the `home-mangler.${hostname}` output attribute in your flake.
     #[arg(long, alias = "host", env = "HOSTNAME")]
     pub hostname: Option<String>,
+
+    /// Print a JSON summary of the changes made to stdout.
+    #[arg(long)]
+    pub json: bool,
+
+    /// Don't ask for confirmation before removing packages from the profile.
+    #[arg(short, long)]
+    pub yes: bool,
+
+    /// Print how long each phase of the run took when it finishes.
+    #[arg(long)]
+    pub timings: bool,
+
+    /// Write a Chrome trace-event JSON file of the run's phases to the given path.
+    ///
+    /// Open it in `chrome://tracing` or https://ui.perfetto.dev/.
+    #[arg(long, value_name = "PATH")]
+    pub chrome_trace: Option<Utf8PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Arguments whose values were read from environment variables rather than the command
+    /// line.
+    #[arg(skip)]
+    pub from_env: BTreeSet<String>,
+}
+
+#[derive(clap::Subcommand)]
+pub enum Command {
+    /// Show the history of previous switches, most recent first.
+    Log(LogArgs),
+
+    /// Inspect configuration.
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+
+    /// Convert a profile managed by `nix-env` to a `nix profile` profile containing the
+    /// home-mangler packages.
+    ///
+    /// The packages installed with `nix-env` are recorded in the state directory first.
+    MigrateProfile(MigrateProfileArgs),
+
+    /// Show the packages in the Nix profile.
+    Status(StatusArgs),
+
+    /// Write a Nix snippet adding packages installed outside of home-mangler to your
+    /// configuration.
+    Adopt(AdoptArgs),
+
+    /// Delete old generations of the profile, and optionally unreachable Nix store paths.
+    ///
+    /// Defaults to the `[gc]` configuration section. Without `--keep-generations` or
+    /// `--older-than`, all generations but the current one are deleted.
+    Gc(GcArgs),
+}
+
+#[derive(clap::Subcommand)]
+pub enum ConfigCommand {
+    /// Show the effective configuration.
+    Show(ConfigShowArgs),
+
+    /// Print a JSON Schema for `config.toml`.
+    Schema,
+
+    /// Print the path of the user configuration file.
+    Path,
+
+    /// Print the effective value of a configuration key, like `update` or `host.NAME.profile`.
+    Get { key: String },
+
+    /// Set a key in the user configuration file.
+    Set {
+        key: String,
+        /// The value to set, parsed as TOML if possible and used as a string otherwise.
+        value: String,
+    },
+
+    /// Open the user configuration file in `$VISUAL` or `$EDITOR`, checking it after editing.
+    Edit,
+}
+
+#[derive(clap::Args)]
+pub struct ConfigShowArgs {
+    /// Show where each value was set.
+    #[arg(long)]
+    pub origin: bool,
+}
+
+#[derive(clap::Args)]
+pub struct MigrateProfileArgs {
+    /// Show which `nix-env` packages would be dropped, without changing the profile.
+    ///
+    /// This still builds the home-mangler packages, because the `nix-env` packages which are
+    /// kept are found in their runtime closure.
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+#[derive(clap::Args)]
+pub struct StatusArgs {
+    /// List the packages in the profile which weren't installed by home-mangler.
+    #[arg(long)]
+    pub unmanaged: bool,
+}
+
+#[derive(clap::Args)]
+pub struct AdoptArgs {
+    /// Write the snippet to this file instead of printing it.
+    #[arg(long, value_name = "PATH")]
+    pub write: Option<Utf8PathBuf>,
+
+    /// Remove the adopted packages from the profile, so that they're only installed through
+    /// home-mangler.
+    #[arg(long)]
+    pub remove: bool,
+}
+
+#[derive(clap::Args)]
+pub struct GcArgs {
+    /// Keep only the newest `N` generations of the profile.
+    #[arg(long, value_name = "N")]
+    pub keep_generations: Option<u32>,
+
+    /// Delete generations older than this many days, like `30d`.
+    #[arg(long, value_name = "AGE")]
+    pub older_than: Option<Age>,
+
+    /// Also run `nix store gc` to delete unreachable store paths.
+    #[arg(long)]
+    pub store: bool,
+}
+
+#[derive(clap::Args)]
+pub struct LogArgs {
+    /// Show only the most recent `LIMIT` entries.
+    #[arg(short = 'n', long)]
+    pub limit: Option<usize>,
+
+    /// Print entries as JSON lines.
+    #[arg(long)]
+    pub json: bool,
 }
 
 impl Args {
+    /// Parse arguments from the command line, recording which values came from the
+    /// environment.
+    pub fn parse_with_sources() -> Self {
+        let matches = Self::command().get_matches();
+        let mut args = Self::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+        args.from_env = matches
+            .ids()
+            .filter(|id| matches.value_source(id.as_str()) == Some(ValueSource::EnvVariable))
+            .map(|id| id.to_string())
+            .collect();
+        args
+    }
+
+    /// Where the value for the argument with the given ID came from.
+    fn config_source(&self, id: &str) -> ConfigSource {
+        let command = Self::command();
+        let arg = command.get_arguments().find(|arg| arg.get_id() == id);
+
+        if self.from_env.contains(id) {
+            if let Some(var) = arg.and_then(|arg| arg.get_env()) {
+                return ConfigSource::Environment(var.to_string_lossy().into_owned());
+            }
+        }
+
+        let long = arg.and_then(|arg| arg.get_long()).unwrap_or(id);
+        ConfigSource::CommandLine(format!("--{long}"))
+    }
+
+    /// Configuration set by command-line flags.
+    pub fn config_layers(&self) -> miette::Result<Vec<ConfigLayer>> {
+        let mut ret = Vec::new();
+        let mut layer = |id: &str, values: Vec<(&str, toml::Value)>| -> miette::Result<()> {
+            let table = values
+                .into_iter()
+                .map(|(key, value)| (key.to_owned(), value))
+                .collect();
+            ret.push(ConfigLayer::new(self.config_source(id), table)?);
+            Ok(())
+        };
+
+        if let Some(flake) = &self.flake {
+            layer("flake", vec![("flake", flake.as_str().into())])?;
+        }
+        if self.update {
+            layer("update", vec![("update", true.into())])?;
+        }
+        if !self.update_input.is_empty() {
+            layer(
+                "update_input",
+                vec![
+                    ("update", true.into()),
+                    ("update-inputs", self.update_input.clone().into()),
+                ],
+            )?;
+        }
+        if self.use_path_flake {
+            layer("use_path_flake", vec![("use-path-flake", true.into())])?;
+        }
+        if let Some(profile) = &self.profile {
+            layer("profile", vec![("profile", profile.as_str().into())])?;
+        }
+
+        Ok(ret)
+    }
+
+    pub fn hostname(&self) -> miette::Result<String> {
+        match &self.hostname {
+            Some(hostname) => Ok(hostname.clone()),
+            None => gethostname::gethostname()
+                .into_string()
+                .map_err(|s| miette!("Hostname is not UTF-8: {s:?}")),
+        }
+    }
+
     pub fn config_paths(&self, project_paths: &ProjectPaths) -> miette::Result<Vec<Utf8PathBuf>> {
         if let Some(path) = &self.config {
             return Ok(vec![path.clone()]);
@@ -84,4 +325,11 @@ impl Args {
             Some(ret)
         }
     }
+
+    pub fn timings_options(&self) -> TimingsOptions {
+        TimingsOptions {
+            print: self.timings,
+            chrome_trace: self.chrome_trace.clone(),
+        }
+    }
 }
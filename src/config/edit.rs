@@ -0,0 +1,331 @@
+use std::io::Write;
+use std::process::Command;
+
+use camino::Utf8Path;
+use command_error::CommandExt;
+use miette::miette;
+use miette::Context;
+use miette::IntoDiagnostic;
+use toml_edit::DocumentMut;
+use toml_edit::Item;
+use toml_edit::Key;
+use toml_edit::TableLike;
+
+use super::layers::display_key_path;
+use super::layers::normalize_key;
+use super::validate::validate_file;
+use super::Config;
+
+/// Print the value of a configuration key.
+pub fn get(config: &Config, key: &str) -> miette::Result<()> {
+    let path = parse_key_path(key)?;
+    match config.layers().get(&path) {
+        Some(toml::Value::String(value)) => println!("{value}"),
+        Some(value) => println!("{value}"),
+        None => {
+            return Err(miette!(
+                "Configuration key `{}` is not set",
+                display_key_path(&path)
+            ))
+        }
+    }
+    Ok(())
+}
+
+/// Set a configuration key in the user configuration file, preserving formatting and comments.
+///
+/// The value is parsed as TOML if possible; otherwise, it's used as a string.
+pub fn set(path: &Utf8Path, key: &str, value: &str) -> miette::Result<()> {
+    let contents = read_or_empty(path)?;
+    let mut document = contents
+        .parse::<DocumentMut>()
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to parse {path}"))?;
+
+    let key_path = parse_key_path(key)?;
+    set_in_document(&mut document, &key_path, value)?;
+
+    let new_contents = document.to_string();
+    validate_file(path, &new_contents)?;
+    write(path, &new_contents)?;
+    tracing::info!("Set `{}` in {path}", display_key_path(&key_path));
+    Ok(())
+}
+
+/// Set the key at `key_path` in `document`.
+///
+/// Existing keys spelled differently (like `use_path_flake` for `use-path-flake`) are reused,
+/// so the file never contains both spellings.
+fn set_in_document(
+    document: &mut DocumentMut,
+    key_path: &[String],
+    value: &str,
+) -> miette::Result<()> {
+    let mut value = value
+        .parse::<toml_edit::Value>()
+        .unwrap_or_else(|_| value.into());
+    let (leaf, parents) = key_path
+        .split_last()
+        .ok_or_else(|| miette!("Configuration key is empty"))?;
+
+    // Hostnames, in `host.<name>`, are compared exactly.
+    let is_hostname = |i: usize| i == 1 && key_path[0] == "host";
+
+    let mut table = document.as_table_mut() as &mut dyn TableLike;
+    for (i, part) in parents.iter().enumerate() {
+        let key = existing_key(table, part, is_hostname(i));
+        let item = table.entry(&key).or_insert_with(|| {
+            let mut table = toml_edit::Table::new();
+            table.set_implicit(true);
+            Item::Table(table)
+        });
+        table = item.as_table_like_mut().ok_or_else(|| {
+            miette!(
+                "Can't set `{}` because `{key}` is not a table",
+                display_key_path(key_path)
+            )
+        })?;
+    }
+
+    let key = existing_key(table, leaf, is_hostname(parents.len()));
+    match table.get_mut(&key) {
+        Some(item) => {
+            // Keep comments and whitespace around the old value.
+            if let Some(old) = item.as_value() {
+                *value.decor_mut() = old.decor().clone();
+            }
+            *item = Item::Value(value);
+        }
+        None => {
+            table.insert(&key, Item::Value(value));
+        }
+    }
+    Ok(())
+}
+
+/// The key in `table` which normalizes to `key`, or `key` itself if there isn't one.
+fn existing_key(table: &dyn TableLike, key: &str, exact: bool) -> String {
+    if exact {
+        return key.to_owned();
+    }
+    table
+        .iter()
+        .map(|(existing, _)| existing)
+        .find(|existing| normalize_key(existing) == key)
+        .unwrap_or(key)
+        .to_owned()
+}
+
+/// Open the user configuration file in `$VISUAL` or `$EDITOR`.
+///
+/// The file is edited in a temporary copy, which replaces the configuration file once it's
+/// valid. If it's invalid, the errors are shown and the editor is opened again.
+pub fn edit(path: &Utf8Path) -> miette::Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| miette!("Configuration path has no file name: {path}"))?;
+    let edit_path = path.with_file_name(format!(".{file_name}.edit.toml"));
+
+    write(&edit_path, &read_or_empty(path)?)?;
+
+    loop {
+        run_editor(&edit_path)?;
+
+        let contents = std::fs::read_to_string(&edit_path)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to read {edit_path}"))?;
+
+        match validate_file(path, &contents) {
+            Ok(()) => {
+                std::fs::rename(&edit_path, path)
+                    .into_diagnostic()
+                    .wrap_err_with(|| format!("Failed to move {edit_path} to {path}"))?;
+                tracing::info!("Saved {path}");
+                return Ok(());
+            }
+            Err(err) => {
+                eprintln!("{err:?}");
+                eprint!("Press Enter to edit the file again, or Ctrl-D to discard your changes: ");
+                std::io::stderr().flush().into_diagnostic()?;
+                let mut line = String::new();
+                if std::io::stdin().read_line(&mut line).into_diagnostic()? == 0 {
+                    let _ = std::fs::remove_file(&edit_path);
+                    return Err(miette!("Discarded invalid changes to {path}"));
+                }
+            }
+        }
+    }
+}
+
+fn run_editor(path: &Utf8Path) -> miette::Result<()> {
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_owned());
+    let words = shell_words::split(&editor)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to parse editor command: {editor}"))?;
+    let (program, args) = words
+        .split_first()
+        .ok_or_else(|| miette!("Editor command is empty"))?;
+
+    Command::new(program)
+        .args(args)
+        .arg(path)
+        .status_checked()
+        .into_diagnostic()
+        .map(|_| ())
+}
+
+fn read_or_empty(path: &Utf8Path) -> miette::Result<String> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => Ok(contents),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(String::new()),
+        Err(err) => Err(err)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to read {path}")),
+    }
+}
+
+fn write(path: &Utf8Path, contents: &str) -> miette::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to create directory {parent}"))?;
+    }
+    std::fs::write(path, contents)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to write {path}"))
+}
+
+/// Parse a dotted key path, like `gc.keep-generations` or `host."my.box".profile`.
+///
+/// Each part is normalized, except for hostnames in `host.<name>`.
+fn parse_key_path(key: &str) -> miette::Result<Vec<String>> {
+    let parts = Key::parse(key).map_err(|err| {
+        miette!(
+            "Invalid configuration key `{key}`: {}",
+            err.message().trim()
+        )
+    })?;
+    Ok(parts
+        .iter()
+        .enumerate()
+        .map(|(i, part)| {
+            if i == 1 && parts[0].get() == "host" {
+                part.get().to_owned()
+            } else {
+                normalize_key(part.get())
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Set `key` to `value` in `contents`, checking that the result is still valid.
+    fn set_str(contents: &str, key: &str, value: &str) -> String {
+        let mut document = contents.parse::<DocumentMut>().unwrap();
+        set_in_document(&mut document, &parse_key_path(key).unwrap(), value).unwrap();
+        let ret = document.to_string();
+        validate_file(Utf8Path::new("config.toml"), &ret).unwrap();
+        ret
+    }
+
+    #[test]
+    fn test_set_new_key() {
+        assert_eq!(set_str("", "update", "true"), "update = true\n");
+        assert_eq!(
+            set_str("update = true\n", "gc.keep-generations", "5"),
+            "update = true\n\n[gc]\nkeep-generations = 5\n"
+        );
+        // Values which aren't valid TOML are used as strings.
+        assert_eq!(
+            set_str("", "profile", "/nix/var/nix/profiles/default"),
+            "profile = \"/nix/var/nix/profiles/default\"\n"
+        );
+    }
+
+    #[test]
+    fn test_set_existing_key() {
+        assert_eq!(
+            set_str(
+                "# Comment\nupdate = false # Another comment\n",
+                "update",
+                "true"
+            ),
+            "# Comment\nupdate = true # Another comment\n"
+        );
+    }
+
+    #[test]
+    fn test_set_snake_case_key() {
+        assert_eq!(
+            set_str("use_path_flake = true\n", "use-path-flake", "false"),
+            "use_path_flake = false\n"
+        );
+        assert_eq!(
+            set_str("[gc]\nkeep_generations = 3\n", "gc.keep-generations", "4"),
+            "[gc]\nkeep_generations = 4\n"
+        );
+        assert_eq!(
+            set_str("log_filters = \"debug\"\n", "log_filter", "info"),
+            "log_filters = \"info\"\n"
+        );
+    }
+
+    #[test]
+    fn test_set_host_key() {
+        assert_eq!(
+            set_str(
+                "[host.my_box]\nprofile = \"/a\"\n",
+                "host.my_box.profile",
+                "/b"
+            ),
+            "[host.my_box]\nprofile = \"/b\"\n"
+        );
+        // Hostnames aren't normalized.
+        assert_eq!(
+            set_str("[host.my_box]\n", "host.my-box.update", "true"),
+            "[host.my_box]\n\n[host.my-box]\nupdate = true\n"
+        );
+        assert_eq!(
+            set_str(
+                "[host.\"my.box\"]\n",
+                "host.\"my.box\".use_path_flake",
+                "true"
+            ),
+            "[host.\"my.box\"]\nuse-path-flake = true\n"
+        );
+    }
+
+    #[test]
+    fn test_set_not_a_table() {
+        let mut document = "gc = 1\n".parse::<DocumentMut>().unwrap();
+        assert!(
+            set_in_document(&mut document, &parse_key_path("gc.store").unwrap(), "true").is_err()
+        );
+    }
+
+    #[test]
+    fn test_parse_key_path() {
+        assert_eq!(
+            parse_key_path("gc.keep_generations").unwrap(),
+            vec!["gc", "keep-generations"]
+        );
+        assert_eq!(
+            parse_key_path("host.\"my.box\".use_path_flake").unwrap(),
+            vec!["host", "my.box", "use-path-flake"]
+        );
+        assert_eq!(
+            parse_key_path("host.my_box.profile").unwrap(),
+            vec!["host", "my_box", "profile"]
+        );
+        assert!(parse_key_path("host..profile").is_err());
+        assert_eq!(
+            display_key_path(&parse_key_path("host.\"my.box\".profile").unwrap()),
+            "host.\"my.box\".profile"
+        );
+    }
+}
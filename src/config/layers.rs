@@ -0,0 +1,465 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+
+use camino::Utf8Path;
+use camino::Utf8PathBuf;
+use miette::Context;
+use miette::IntoDiagnostic;
+use toml::Table;
+use toml::Value;
+
+use super::ConfigFile;
+
+/// Environment variables starting with this prefix set configuration keys, like
+/// `HOME_MANGLER_UPDATE=true`.
+const ENV_PREFIX: &str = "HOME_MANGLER_";
+
+/// Environment variables with [`ENV_PREFIX`] which are handled elsewhere.
+const ENV_IGNORED: &[&str] = &["HOME_MANGLER_LOG", "HOME_MANGLER_LOG_FORMAT"];
+
+/// Old key names and their replacements.
+const KEY_ALIASES: &[(&str, &str)] = &[("log-filters", "log-filter")];
+
+/// Where a configuration value came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigSource {
+    /// A system-wide configuration file, like `/etc/xdg/home-mangler/config.toml`.
+    System(Utf8PathBuf),
+    /// A user configuration file, like `~/.config/home-mangler/config.toml`.
+    User(Utf8PathBuf),
+    /// A `config.toml` next to the flake.
+    Repo(Utf8PathBuf),
+    /// A `[host.<name>]` table in another source.
+    Host(Box<ConfigSource>, String),
+    /// An environment variable.
+    Environment(String),
+    /// A command-line flag.
+    CommandLine(String),
+}
+
+impl ConfigSource {
+    pub fn path(&self) -> Option<&Utf8Path> {
+        match self {
+            ConfigSource::System(path) | ConfigSource::User(path) | ConfigSource::Repo(path) => {
+                Some(path)
+            }
+            ConfigSource::Host(_, _)
+            | ConfigSource::Environment(_)
+            | ConfigSource::CommandLine(_) => None,
+        }
+    }
+}
+
+impl Display for ConfigSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigSource::System(path) => write!(f, "system config {path}"),
+            ConfigSource::User(path) => write!(f, "user config {path}"),
+            ConfigSource::Repo(path) => write!(f, "repository config {path}"),
+            ConfigSource::Host(source, hostname) => write!(f, "[host.{hostname}] in {source}"),
+            ConfigSource::Environment(var) => write!(f, "environment variable ${var}"),
+            ConfigSource::CommandLine(flag) => write!(f, "command-line flag `{flag}`"),
+        }
+    }
+}
+
+/// A set of configuration values from a single source.
+#[derive(Debug, Clone)]
+pub struct ConfigLayer {
+    pub source: ConfigSource,
+    pub table: Table,
+}
+
+impl ConfigLayer {
+    /// Create a layer, checking that its values are valid.
+    pub fn new(source: ConfigSource, mut table: Table) -> miette::Result<Self> {
+        normalize_keys(&mut table);
+        ConfigFile::from_table(table.clone())
+            .wrap_err_with(|| format!("Invalid configuration in {source}"))?;
+        Ok(Self { source, table })
+    }
+
+    pub fn from_path(source: ConfigSource, path: &Utf8Path) -> miette::Result<Self> {
+        tracing::debug!(%path, "Loading configuration");
+        let contents = std::fs::read_to_string(path)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to read {path}"))?;
+        super::validate::validate_file(path, &contents)?;
+        let table = contents
+            .parse::<Table>()
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to parse {path}"))?;
+        Self::new(source, table)
+    }
+
+    /// Read configuration from `HOME_MANGLER_*` environment variables.
+    ///
+    /// Values are parsed as TOML if possible, so `HOME_MANGLER_UPDATE=true` sets `update` to a
+    /// boolean. Other values, like `HOME_MANGLER_FLAKE=~/dotfiles`, are treated as strings.
+    pub fn from_env() -> miette::Result<Vec<Self>> {
+        let mut ret = Vec::new();
+        for (var, value) in std::env::vars() {
+            let key = match var.strip_prefix(ENV_PREFIX) {
+                Some(key) if !ENV_IGNORED.contains(&var.as_str()) => {
+                    key.to_lowercase().replace('_', "-")
+                }
+                _ => continue,
+            };
+
+            if !ConfigFile::KEYS.contains(&key.as_str()) {
+                tracing::debug!(%var, "Ignoring unknown configuration environment variable");
+                continue;
+            }
+
+            let value = format!("value = {value}")
+                .parse::<Table>()
+                .ok()
+                .and_then(|mut table| table.remove("value"))
+                .unwrap_or(Value::String(value));
+
+            let mut table = Table::new();
+            table.insert(key, value);
+            ret.push(Self::new(ConfigSource::Environment(var), table)?);
+        }
+        Ok(ret)
+    }
+}
+
+/// Configuration layers merged together, later layers taking precedence over earlier ones.
+#[derive(Debug, Clone, Default)]
+pub struct LayeredConfig {
+    layers: Vec<ConfigLayer>,
+    merged: Table,
+    /// Map from key paths to the source that set them.
+    origins: BTreeMap<Vec<String>, ConfigSource>,
+}
+
+impl LayeredConfig {
+    /// Merge the given layers, applying the `[host.<name>]` tables for `hostname`.
+    pub fn new(layers: Vec<ConfigLayer>, hostname: &str) -> Self {
+        let mut merged = Table::new();
+        let mut origins = BTreeMap::new();
+        for layer in &layers {
+            merge_table(&mut merged, &layer.table, &layer.source, &[], &mut origins);
+
+            if let Some(Value::Table(host)) = layer
+                .table
+                .get("host")
+                .and_then(|hosts| hosts.get(hostname))
+            {
+                let source =
+                    ConfigSource::Host(Box::new(layer.source.clone()), hostname.to_owned());
+                merge_table(&mut merged, host, &source, &[], &mut origins);
+            }
+        }
+        Self {
+            layers,
+            merged,
+            origins,
+        }
+    }
+
+    pub fn layers(&self) -> &[ConfigLayer] {
+        &self.layers
+    }
+
+    pub fn merged(&self) -> &Table {
+        &self.merged
+    }
+
+    /// Get a value by its key path, like `["host", "grandiflora", "profile"]`.
+    pub fn get(&self, path: &[String]) -> Option<&Value> {
+        let (leaf, parents) = path.split_last()?;
+        parents
+            .iter()
+            .try_fold(&self.merged, |table, part| match table.get(part) {
+                Some(Value::Table(table)) => Some(table),
+                _ => None,
+            })
+            .and_then(|table| table.get(leaf))
+    }
+
+    /// Which source set each value, by key path.
+    pub fn origins(&self) -> &BTreeMap<Vec<String>, ConfigSource> {
+        &self.origins
+    }
+
+    pub fn config_file(&self) -> miette::Result<ConfigFile> {
+        ConfigFile::from_table(self.merged.clone())
+    }
+}
+
+/// Deep-merge `overlay` into `base`. Tables are merged recursively; other values are replaced.
+fn merge_table(
+    base: &mut Table,
+    overlay: &Table,
+    source: &ConfigSource,
+    prefix: &[String],
+    origins: &mut BTreeMap<Vec<String>, ConfigSource>,
+) {
+    for (key, value) in overlay {
+        let mut path = prefix.to_vec();
+        path.push(key.clone());
+
+        match (base.get_mut(key), value) {
+            (Some(Value::Table(base)), Value::Table(overlay)) => {
+                merge_table(base, overlay, source, &path, origins);
+            }
+            _ => {
+                // Anything previously set beneath this key is replaced.
+                origins.retain(|key, _| key.len() <= path.len() || !key.starts_with(&path));
+
+                match value {
+                    Value::Table(table) => {
+                        let mut new = Table::new();
+                        merge_table(&mut new, table, source, &path, origins);
+                        base.insert(key.clone(), Value::Table(new));
+                    }
+                    _ => {
+                        origins.insert(path, source.clone());
+                        base.insert(key.clone(), value.clone());
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Rename `snake_case` keys to `kebab-case` and replace old key names, including in nested
+/// tables like `[gc]` and `[host.<name>]`. Hostnames are left alone.
+pub fn normalize_keys(table: &mut Table) {
+    normalize_table_keys(table);
+
+    for (key, value) in table.iter_mut() {
+        let Value::Table(nested) = value else {
+            continue;
+        };
+        if key == "host" {
+            for (_, host) in nested.iter_mut() {
+                if let Value::Table(host) = host {
+                    normalize_keys(host);
+                }
+            }
+        } else {
+            normalize_keys(nested);
+        }
+    }
+}
+
+fn normalize_table_keys(table: &mut Table) {
+    let keys = table.keys().cloned().collect::<Vec<_>>();
+    for key in keys {
+        let normalized = normalize_key(&key);
+        if normalized != key {
+            if let Some(value) = table.remove(&key) {
+                table.insert(normalized, value);
+            }
+        }
+    }
+}
+
+/// Rename a `snake_case` key to `kebab-case`, replacing old key names.
+pub fn normalize_key(key: &str) -> String {
+    let normalized = key.replace('_', "-");
+    match KEY_ALIASES.iter().find(|(old, _)| *old == normalized) {
+        Some((_, new)) => (*new).to_owned(),
+        None => normalized,
+    }
+}
+
+/// Format a key path for messages, like `host."my.box".profile`.
+pub fn display_key_path(path: &[String]) -> String {
+    path.iter()
+        .map(|part| {
+            toml_edit::Key::new(part.as_str())
+                .display_repr()
+                .into_owned()
+        })
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layer(source: ConfigSource, contents: &str) -> ConfigLayer {
+        ConfigLayer::new(source, contents.parse().unwrap()).unwrap()
+    }
+
+    fn path(key: &str) -> Vec<String> {
+        key.split('.').map(str::to_owned).collect()
+    }
+
+    fn layers() -> Vec<ConfigLayer> {
+        vec![
+            layer(
+                ConfigSource::System("/etc/xdg/home-mangler/config.toml".into()),
+                r#"
+                flake = "/system"
+                profile = "/system-profile"
+                priority = 1
+                update = false
+                deny-bin-conflicts = false
+                [gc]
+                keep-generations = 1
+                older-than = "1d"
+                "#,
+            ),
+            layer(
+                ConfigSource::User("/home/user/.config/home-mangler/config.toml".into()),
+                r#"
+                flake = "/user"
+                profile = "/user-profile"
+                priority = 2
+                update = false
+                [gc]
+                keep-generations = 2
+                [host.box]
+                profile = "/box-profile"
+                [host.other]
+                profile = "/other-profile"
+                "#,
+            ),
+            layer(
+                ConfigSource::Repo("/flake/config.toml".into()),
+                r#"
+                flake = "/repo"
+                priority = 3
+                "#,
+            ),
+            layer(
+                ConfigSource::Environment("HOME_MANGLER_PRIORITY".to_owned()),
+                "priority = 4",
+            ),
+            layer(
+                ConfigSource::CommandLine("--update".to_owned()),
+                "update = true",
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_merge_precedence() {
+        let config = LayeredConfig::new(layers(), "box");
+
+        let get = |key: &str| config.get(&path(key)).cloned();
+        assert_eq!(get("flake"), Some(Value::String("/repo".to_owned())));
+        assert_eq!(get("priority"), Some(Value::Integer(4)));
+        assert_eq!(get("update"), Some(Value::Boolean(true)));
+        assert_eq!(get("deny-bin-conflicts"), Some(Value::Boolean(false)));
+        // Host tables take precedence over the layer they're in.
+        assert_eq!(
+            get("profile"),
+            Some(Value::String("/box-profile".to_owned()))
+        );
+        // Tables are merged rather than replaced.
+        assert_eq!(get("gc.keep-generations"), Some(Value::Integer(2)));
+        assert_eq!(get("gc.older-than"), Some(Value::String("1d".to_owned())));
+    }
+
+    #[test]
+    fn test_merge_origins() {
+        let config = LayeredConfig::new(layers(), "box");
+
+        let origin = |key: &str| config.origins().get(&path(key)).cloned();
+        assert_eq!(
+            origin("flake"),
+            Some(ConfigSource::Repo("/flake/config.toml".into()))
+        );
+        assert_eq!(
+            origin("priority"),
+            Some(ConfigSource::Environment(
+                "HOME_MANGLER_PRIORITY".to_owned()
+            ))
+        );
+        assert_eq!(
+            origin("update"),
+            Some(ConfigSource::CommandLine("--update".to_owned()))
+        );
+        assert_eq!(
+            origin("deny-bin-conflicts"),
+            Some(ConfigSource::System(
+                "/etc/xdg/home-mangler/config.toml".into()
+            ))
+        );
+        assert_eq!(
+            origin("profile"),
+            Some(ConfigSource::Host(
+                Box::new(ConfigSource::User(
+                    "/home/user/.config/home-mangler/config.toml".into()
+                )),
+                "box".to_owned()
+            ))
+        );
+        assert_eq!(
+            origin("gc.older-than"),
+            Some(ConfigSource::System(
+                "/etc/xdg/home-mangler/config.toml".into()
+            ))
+        );
+        assert_eq!(origin("gc"), None);
+    }
+
+    #[test]
+    fn test_merge_replaces_origins_beneath_key() {
+        let source = ConfigSource::CommandLine("--gc".to_owned());
+        let mut merged = Table::new();
+        let mut origins = BTreeMap::new();
+        merge_table(
+            &mut merged,
+            &"gc = { keep-generations = 1 }".parse().unwrap(),
+            &source,
+            &[],
+            &mut origins,
+        );
+        merge_table(
+            &mut merged,
+            &"gc = false".parse().unwrap(),
+            &source,
+            &[],
+            &mut origins,
+        );
+
+        assert_eq!(merged.get("gc"), Some(&Value::Boolean(false)));
+        assert_eq!(origins.keys().collect::<Vec<_>>(), vec![&path("gc")]);
+    }
+
+    #[test]
+    fn test_normalize_keys() {
+        let mut table: Table = r#"
+            use_path_flake = true
+            log-filters = "debug"
+            [gc]
+            keep_generations = 3
+            [host.my_box]
+            deny_bin_conflicts = true
+            [host.my_box.gc]
+            older_than = "30d"
+            "#
+        .parse()
+        .unwrap();
+        normalize_keys(&mut table);
+
+        let expected: Table = r#"
+            use-path-flake = true
+            log-filter = "debug"
+            [gc]
+            keep-generations = 3
+            [host.my_box]
+            deny-bin-conflicts = true
+            [host.my_box.gc]
+            older-than = "30d"
+            "#
+        .parse()
+        .unwrap();
+        assert_eq!(table, expected);
+    }
+
+    #[test]
+    fn test_normalize_key() {
+        assert_eq!(normalize_key("use_path_flake"), "use-path-flake");
+        assert_eq!(normalize_key("use-path-flake"), "use-path-flake");
+        assert_eq!(normalize_key("log_filters"), "log-filter");
+    }
+}
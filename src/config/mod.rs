@@ -0,0 +1,680 @@
+use std::collections::BTreeMap;
+use std::io::IsTerminal;
+
+use camino::Utf8Path;
+use camino::Utf8PathBuf;
+use miette::miette;
+use miette::Context;
+use miette::IntoDiagnostic;
+
+use crate::cli::Args;
+use crate::cli::Command;
+use crate::cli::ConfigCommand;
+use crate::cli::ConfigShowArgs;
+use crate::cli::GcArgs;
+use crate::flake::Flake;
+use crate::format_bulleted_list;
+use crate::gc::Age;
+use crate::gc::GcOptions;
+use crate::gc::GcRoots;
+use crate::gc::DEFAULT_KEEP_GENERATIONS;
+use crate::nix::Nix;
+use crate::nix::NixEnvironment;
+use crate::nix::DEFAULT_PRIORITY;
+use crate::packages::PackagesOptions;
+use crate::tracing::LogFormat;
+use crate::update::UpdateOptions;
+use crate::ProjectPaths;
+
+mod edit;
+mod layers;
+mod schema;
+mod validate;
+pub use layers::ConfigLayer;
+pub use layers::ConfigSource;
+pub use layers::LayeredConfig;
+use schema::config_schema;
+
+/// A step of the switch process, which may be enabled or disabled.
+#[derive(serde::Deserialize, schemars::JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Step {
+    /// Build and install packages to the Nix profile.
+    Packages,
+}
+
+/// How to treat `nix profile` packages not installed by home-mangler.
+#[derive(serde::Deserialize, schemars::JsonSchema, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProfileMode {
+    /// Leave other packages in the profile alone.
+    #[default]
+    Additive,
+    /// Remove every package not produced by `home-mangler.${hostname}.packages`.
+    Strict,
+}
+
+/// Garbage collection settings, for `home-mangler gc` and after switching.
+#[derive(serde::Deserialize, schemars::JsonSchema, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct GcConfig {
+    /// Collect garbage after each switch which changes the profile.
+    ///
+    /// Unless `keep-generations` or `older-than` is set, the newest 5 generations are kept.
+    after_switch: Option<bool>,
+    /// Keep only the newest `N` generations of the profile.
+    keep_generations: Option<u32>,
+    /// Delete generations older than this many days, like `"30d"`.
+    older_than: Option<Age>,
+    /// Also run `nix store gc` to delete unreachable store paths.
+    store: Option<bool>,
+    /// Register built packages as garbage collector roots in
+    /// `$XDG_STATE_HOME/home-mangler/gcroots`, keeping the newest `N` builds.
+    ///
+    /// This keeps builds from being deleted before they're installed, and keeps old builds
+    /// around so that rolling back never requires rebuilding.
+    keep_roots: Option<u32>,
+}
+
+/// One or more `tracing` filter directives.
+#[derive(serde::Deserialize, schemars::JsonSchema)]
+#[serde(untagged)]
+enum LogFilter {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// Configuration loaded from a file.
+///
+/// Keys are written in `kebab-case`; `snake_case` keys are normalized when loaded.
+#[derive(serde::Deserialize, schemars::JsonSchema, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+#[schemars(title = "home-mangler configuration")]
+pub struct ConfigFile {
+    /// Tracing log filter directives.
+    ///
+    /// See: https://docs.rs/tracing-subscriber/latest/tracing_subscriber/filter/struct.EnvFilter.html#directives
+    log_filter: Option<LogFilter>,
+    /// Also write JSON logs to a daily-rotated file in `$XDG_STATE_HOME/home-mangler/logs`.
+    log_file: Option<bool>,
+    /// Flake containing home-mangler configuration.
+    ///
+    /// Defaults to the directory containing the configuration file.
+    flake: Option<String>,
+    /// Update flake inputs with `nix flake update` before building configuration.
+    update: Option<bool>,
+    /// Update only these flake inputs. Implies `update` unless it's set to `false`.
+    update_inputs: Option<Vec<String>>,
+    /// Commit `flake.lock` after updating it, if the flake is a local Git checkout.
+    auto_commit_lock: Option<bool>,
+    /// Refuse to switch from a Git flake with uncommitted changes.
+    require_clean: Option<bool>,
+    /// Use a path flake (instead of a Git flake or similar) for local flakes.
+    use_path_flake: Option<bool>,
+    /// Profile to use for `nix profile` operations.
+    #[schemars(with = "Option<String>")]
+    profile: Option<Utf8PathBuf>,
+    /// Steps to run. Defaults to all steps.
+    steps: Option<Vec<Step>>,
+    /// `"additive"` (the default) leaves packages installed outside of home-mangler in the
+    /// profile; `"strict"` removes them after building.
+    profile_mode: Option<ProfileMode>,
+    /// Priority to install the home-mangler packages with. When several packages in the
+    /// profile provide the same file, the one with the lowest priority number wins.
+    ///
+    /// Defaults to 5, like `nix profile install`.
+    priority: Option<u16>,
+    /// Fail when several packages in the configuration provide the same file in `bin/`.
+    ///
+    /// Otherwise, files shadowed in the package set are reported as warnings.
+    deny_bin_conflicts: Option<bool>,
+    /// Garbage collection settings.
+    gc: Option<GcConfig>,
+    /// The environment to run `nix` in: `"inherit"` (the default), `"login-shell"`, or
+    /// `{ source = "/path/to/nix-daemon.sh" }`.
+    ///
+    /// The environment is captured once and used for every `nix` command, which is useful
+    /// when home-mangler runs from a systemd timer or `cron`.
+    environment: Option<NixEnvironment>,
+    /// Per-host overrides, keyed by hostname.
+    // This is only deserialized to check the values; the overrides are applied by
+    // `LayeredConfig`.
+    #[allow(dead_code)]
+    host: Option<BTreeMap<String, HostConfig>>,
+}
+
+/// Configuration in a `[host.<name>]` table, which overrides top-level keys when switching
+/// for that hostname.
+#[derive(serde::Deserialize, schemars::JsonSchema)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+#[allow(dead_code)]
+struct HostConfig {
+    /// Tracing log filter directives.
+    log_filter: Option<LogFilter>,
+    /// Flake containing home-mangler configuration.
+    flake: Option<String>,
+    /// Update flake inputs with `nix flake update` before building configuration.
+    update: Option<bool>,
+    /// Use a path flake (instead of a Git flake or similar) for local flakes.
+    use_path_flake: Option<bool>,
+    /// Profile to use for `nix profile` operations.
+    #[schemars(with = "Option<String>")]
+    profile: Option<Utf8PathBuf>,
+    /// Steps to run. Defaults to all steps.
+    steps: Option<Vec<Step>>,
+    /// Whether to remove packages installed outside of home-mangler.
+    profile_mode: Option<ProfileMode>,
+    /// Priority to install the home-mangler packages with.
+    priority: Option<u16>,
+    /// Fail when several packages in the configuration provide the same file in `bin/`.
+    deny_bin_conflicts: Option<bool>,
+    /// Garbage collection settings.
+    gc: Option<GcConfig>,
+    /// The environment to run `nix` in.
+    environment: Option<NixEnvironment>,
+}
+
+impl GcConfig {
+    pub const KEYS: &'static [&'static str] = &[
+        "after-switch",
+        "keep-generations",
+        "older-than",
+        "store",
+        "keep-roots",
+    ];
+}
+
+impl HostConfig {
+    pub const KEYS: &'static [&'static str] = &[
+        "log-filter",
+        "flake",
+        "update",
+        "use-path-flake",
+        "profile",
+        "steps",
+        "profile-mode",
+        "priority",
+        "deny-bin-conflicts",
+        "gc",
+        "environment",
+    ];
+}
+
+impl ConfigFile {
+    /// Top-level keys, for reading configuration from environment variables.
+    pub const KEYS: &'static [&'static str] = &[
+        "log-filter",
+        "log-file",
+        "flake",
+        "update",
+        "update-inputs",
+        "auto-commit-lock",
+        "require-clean",
+        "use-path-flake",
+        "profile",
+        "steps",
+        "profile-mode",
+        "priority",
+        "deny-bin-conflicts",
+        "gc",
+        "environment",
+    ];
+
+    pub fn from_table(table: toml::Table) -> miette::Result<Self> {
+        toml::Value::Table(table).try_into().into_diagnostic()
+    }
+}
+
+pub struct Config {
+    /// The highest-precedence user configuration file, if any.
+    path: Option<Utf8PathBuf>,
+    /// The hostname to build the configuration for, used to select `[host.<name>]` tables.
+    hostname: String,
+    project_paths: ProjectPaths,
+    layers: LayeredConfig,
+    file: ConfigFile,
+    args: Args,
+}
+
+impl Config {
+    /// Load configuration.
+    ///
+    /// Sources are merged in this order, later sources taking precedence:
+    ///
+    /// 1. System configuration files (`$XDG_CONFIG_DIRS/home-mangler/config.toml`).
+    /// 2. User configuration files (`~/.config/home-mangler/config.toml`), or `--config`.
+    /// 3. A `config.toml` next to the flake, if it's a local path.
+    /// 4. `HOME_MANGLER_*` environment variables.
+    /// 5. Command-line flags.
+    ///
+    /// Within each configuration file, values in the `[host.<name>]` table for the current
+    /// hostname take precedence over top-level values.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub fn from_args(args: Args) -> miette::Result<Self> {
+        let project_paths = ProjectPaths::new()?;
+        let hostname = args.hostname()?;
+
+        let mut file_layers = Vec::new();
+        let mut loaded_paths = Vec::new();
+
+        let system_paths = project_paths.system_config_paths();
+        tracing::trace!(paths = ?system_paths, "Looking for system configuration files");
+        for path in system_paths.into_iter().rev() {
+            if path_exists(&path)? {
+                file_layers.push(ConfigLayer::from_path(
+                    ConfigSource::System(path.clone()),
+                    &path,
+                )?);
+                loaded_paths.push(path);
+            }
+        }
+
+        let user_paths = args.config_paths(&project_paths)?;
+        tracing::trace!(paths = ?user_paths, "Looking for user configuration files");
+        let mut path = None;
+        for user_path in user_paths.into_iter().rev() {
+            if path_exists(&user_path)? && !is_loaded(&loaded_paths, &user_path) {
+                file_layers.push(ConfigLayer::from_path(
+                    ConfigSource::User(user_path.clone()),
+                    &user_path,
+                )?);
+                loaded_paths.push(user_path.clone());
+                path = Some(user_path);
+            }
+        }
+
+        if path.is_none() {
+            tracing::debug!("No user configuration file found");
+        }
+
+        let mut override_layers = ConfigLayer::from_env()?;
+        override_layers.extend(args.config_layers()?);
+
+        let mut config = Self {
+            path,
+            hostname,
+            project_paths,
+            layers: Default::default(),
+            file: Default::default(),
+            args,
+        };
+        config.set_layers(
+            file_layers
+                .iter()
+                .chain(&override_layers)
+                .cloned()
+                .collect(),
+        )?;
+
+        if let Some(repo_layer) = config.repo_layer(&loaded_paths)? {
+            file_layers.push(repo_layer);
+            config.set_layers(file_layers.into_iter().chain(override_layers).collect())?;
+        }
+
+        Ok(config)
+    }
+
+    fn set_layers(&mut self, layers: Vec<ConfigLayer>) -> miette::Result<()> {
+        self.layers = LayeredConfig::new(layers, &self.hostname);
+        self.file = self.layers.config_file()?;
+        Ok(())
+    }
+
+    /// Load the `config.toml` next to the flake, if the flake is a local path.
+    fn repo_layer(&self, loaded_paths: &[Utf8PathBuf]) -> miette::Result<Option<ConfigLayer>> {
+        let flake = match self.flake_unconfigured() {
+            Ok(Flake::Path(flake)) => flake,
+            Ok(Flake::Url(_)) => return Ok(None),
+            Err(err) => {
+                tracing::debug!("Not loading repository configuration: {err}");
+                return Ok(None);
+            }
+        };
+
+        let path = flake.path().join("config.toml");
+        if !path_exists(&path)? || is_loaded(loaded_paths, &path) {
+            return Ok(None);
+        }
+
+        let mut layer = ConfigLayer::from_path(ConfigSource::Repo(path.clone()), &path)?;
+        let mut ignored = Vec::new();
+        if layer.table.remove("flake").is_some() {
+            ignored.push("flake".to_owned());
+        }
+        if let Some(toml::Value::Table(hosts)) = layer.table.get_mut("host") {
+            for (name, host) in hosts.iter_mut() {
+                if let toml::Value::Table(host) = host {
+                    if host.remove("flake").is_some() {
+                        ignored.push(format!("host.{name}.flake"));
+                    }
+                }
+            }
+        }
+        for key in ignored {
+            tracing::warn!("Ignoring `{key}` in {path}; the flake location can't be set by a configuration file inside the flake");
+        }
+        Ok(Some(layer))
+    }
+
+    pub fn log_filter(&self) -> String {
+        let mut ret = String::new();
+        match &self.file.log_filter {
+            Some(LogFilter::One(filter)) => {
+                ret.push_str(filter);
+            }
+            Some(LogFilter::Many(filters)) => {
+                ret.push_str(&filters.join(","));
+            }
+            None => {}
+        }
+
+        if let Some(filter) = &self.args.log_filter() {
+            ret.push(',');
+            ret.push_str(filter);
+        }
+
+        if ret.is_empty() {
+            ret.push_str(crate::tracing::DEFAULT_FILTER);
+        }
+
+        ret
+    }
+
+    /// The directory to write log files to, if enabled.
+    pub fn log_file_dir(&self) -> miette::Result<Option<Utf8PathBuf>> {
+        if self.file.log_file.unwrap_or(false) {
+            self.project_paths.log_dir().map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn packages_options(&self) -> miette::Result<PackagesOptions> {
+        let gc_roots = match self.file.gc.as_ref().and_then(|gc| gc.keep_roots) {
+            Some(keep) if keep > 0 => Some(GcRoots::new(self.project_paths.gc_roots_dir()?, keep)),
+            _ => None,
+        };
+
+        Ok(PackagesOptions {
+            update: self.update(),
+            require_clean: self.file.require_clean.unwrap_or(false),
+            profile_mode: self.file.profile_mode.unwrap_or_default(),
+            assume_yes: self.args.yes,
+            priority: self.file.priority.unwrap_or(DEFAULT_PRIORITY),
+            deny_bin_conflicts: self.file.deny_bin_conflicts.unwrap_or(false),
+            gc_roots,
+        })
+    }
+
+    /// Options for `home-mangler gc`, with `args` taking precedence over the `[gc]` section.
+    ///
+    /// Without `args` (when collecting garbage after a switch), at least
+    /// [`DEFAULT_KEEP_GENERATIONS`] generations are kept unless `keep-generations` or
+    /// `older-than` is set, so that switching never deletes all of the profile's history.
+    pub fn gc_options(&self, args: Option<&GcArgs>) -> GcOptions {
+        let gc = self.file.gc.clone().unwrap_or_default();
+        if args.is_none() && gc.keep_generations.is_none() && gc.older_than.is_none() {
+            return GcOptions {
+                keep_generations: Some(DEFAULT_KEEP_GENERATIONS),
+                older_than: None,
+                store: gc.store.unwrap_or(false),
+            };
+        }
+        GcOptions {
+            keep_generations: args
+                .and_then(|args| args.keep_generations)
+                .or(gc.keep_generations),
+            older_than: args.and_then(|args| args.older_than).or(gc.older_than),
+            store: args.is_some_and(|args| args.store) || gc.store.unwrap_or(false),
+        }
+    }
+
+    /// Collect garbage after switching?
+    pub fn gc_after_switch(&self) -> bool {
+        self.file
+            .gc
+            .as_ref()
+            .and_then(|gc| gc.after_switch)
+            .unwrap_or(false)
+    }
+
+    /// How to update the flake's inputs, if they should be updated.
+    fn update(&self) -> Option<UpdateOptions> {
+        let has_inputs = self
+            .file
+            .update_inputs
+            .as_ref()
+            .is_some_and(|inputs| !inputs.is_empty());
+        match self.file.update {
+            Some(true) => {}
+            None if has_inputs => {}
+            Some(false) if has_inputs => {
+                tracing::warn!("Ignoring `update-inputs` because `update = false` is set");
+                return None;
+            }
+            _ => return None,
+        }
+
+        Some(UpdateOptions {
+            inputs: self.file.update_inputs.clone().unwrap_or_default(),
+            auto_commit_lock: self.file.auto_commit_lock.unwrap_or(false),
+        })
+    }
+
+    pub fn command(&self) -> Option<&Command> {
+        self.args.command.as_ref()
+    }
+
+    pub fn history_path(&self) -> miette::Result<Utf8PathBuf> {
+        self.project_paths.history_path()
+    }
+
+    pub fn state_dir(&self) -> miette::Result<Utf8PathBuf> {
+        self.project_paths.state_dir()
+    }
+
+    pub fn json(&self) -> bool {
+        self.args.json
+    }
+
+    pub fn layers(&self) -> &LayeredConfig {
+        &self.layers
+    }
+
+    fn use_path_flake(&self) -> bool {
+        self.file.use_path_flake.unwrap_or(false)
+    }
+
+    pub fn flake(&self) -> miette::Result<Flake> {
+        Ok(self
+            .flake_unconfigured()?
+            .set_use_path_flake(self.use_path_flake()))
+    }
+
+    fn flake_unconfigured(&self) -> miette::Result<Flake> {
+        if let Some(flake) = &self.file.flake {
+            return flake.parse();
+        }
+
+        let mut paths = self.project_paths.flake_paths()?;
+
+        if let Some(path) = &self.path {
+            paths.push(
+                path.parent()
+                    .ok_or_else(|| miette!("Configuration file has no parent directory: {path}"))?
+                    .join("flake.nix"),
+            );
+        }
+
+        for path in &paths {
+            if path.try_exists().into_diagnostic()? {
+                return path.as_path().try_into();
+            }
+        }
+
+        Err(miette!(
+            "Unable to find home-mangler `flake.nix`. I looked in these paths:\n{}",
+            format_bulleted_list(&paths)
+        ))
+    }
+
+    pub fn hostname(&self) -> &str {
+        &self.hostname
+    }
+
+    /// The steps to run; by default, all steps are run.
+    pub fn steps(&self) -> Vec<Step> {
+        self.file
+            .steps
+            .clone()
+            .unwrap_or_else(|| vec![Step::Packages])
+    }
+
+    pub fn nix(&self) -> miette::Result<Nix> {
+        // The progress display would be interleaved with JSON logs.
+        let progress =
+            matches!(self.args.log_format, LogFormat::Human) && std::io::stderr().is_terminal();
+
+        Ok(
+            Nix::new(&self.file.environment.clone().unwrap_or_default())?
+                .with_profile(self.file.profile.clone())
+                .with_log_dir(Some(self.project_paths.nix_log_dir()?))
+                .with_progress(progress),
+        )
+    }
+}
+
+fn path_exists(path: &Utf8Path) -> miette::Result<bool> {
+    path.try_exists()
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to check if configuration path exists: {path}"))
+}
+
+/// Is `path` the same file as one of `loaded_paths`?
+fn is_loaded(loaded_paths: &[Utf8PathBuf], path: &Utf8Path) -> bool {
+    loaded_paths
+        .iter()
+        .any(|loaded| same_file::is_same_file(loaded, path).unwrap_or(false))
+}
+
+/// The user configuration file to edit: the highest-precedence one that exists, or `--config`,
+/// or the default location if none exists yet.
+///
+/// This doesn't load any configuration, so it works when the configuration is invalid.
+fn user_config_path(args: &Args) -> miette::Result<Utf8PathBuf> {
+    let paths = args.config_paths(&ProjectPaths::new()?)?;
+    for path in &paths {
+        if path_exists(path)? {
+            return Ok(path.clone());
+        }
+    }
+    paths
+        .into_iter()
+        .next()
+        .ok_or_else(|| miette!("No user configuration paths"))
+}
+
+/// Run a `home-mangler config` subcommand which doesn't need the configuration to be loaded,
+/// or `None` if `command` needs it.
+///
+/// These run before the configuration is loaded, so that an invalid configuration file can
+/// still be found and fixed.
+pub fn run_standalone_command(args: &Args, command: &ConfigCommand) -> Option<miette::Result<()>> {
+    Some(match command {
+        ConfigCommand::Schema => config_schema().map(|schema| print!("{schema}")),
+        ConfigCommand::Path => user_config_path(args).map(|path| println!("{path}")),
+        ConfigCommand::Set { key, value } => {
+            user_config_path(args).and_then(|path| edit::set(&path, key, value))
+        }
+        ConfigCommand::Edit => user_config_path(args).and_then(|path| edit::edit(&path)),
+        ConfigCommand::Show(_) | ConfigCommand::Get { .. } => return None,
+    })
+}
+
+/// Run a `home-mangler config` subcommand.
+pub fn run_command(config: &Config, command: &ConfigCommand) -> miette::Result<()> {
+    match command {
+        ConfigCommand::Show(args) => show(config, args),
+        ConfigCommand::Get { key } => edit::get(config, key),
+        ConfigCommand::Schema
+        | ConfigCommand::Path
+        | ConfigCommand::Set { .. }
+        | ConfigCommand::Edit => run_standalone_command(&config.args, command)
+            .unwrap_or_else(|| Err(miette!("Unexpected configuration command"))),
+    }
+}
+
+/// Print the effective configuration.
+fn show(config: &Config, args: &ConfigShowArgs) -> miette::Result<()> {
+    let layers = config.layers();
+
+    if !args.origin {
+        print!("{}", toml::to_string(layers.merged()).into_diagnostic()?);
+        return Ok(());
+    }
+
+    let paths = layers
+        .layers()
+        .iter()
+        .filter(|layer| layer.source.path().is_some())
+        .map(|layer| &layer.source)
+        .collect::<Vec<_>>();
+    if paths.is_empty() {
+        println!("# No configuration files loaded");
+    } else {
+        println!("# Loaded, lowest precedence first:");
+        for source in paths {
+            println!("# • {source}");
+        }
+    }
+
+    for (key, source) in layers.origins() {
+        if let Some(value) = layers.get(key) {
+            println!("{} = {value}  # {source}", layers::display_key_path(key));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use schemars::JsonSchema;
+
+    use super::*;
+
+    /// The keys of the struct `T`, according to its schema.
+    fn schema_keys<T: JsonSchema>() -> BTreeSet<String> {
+        schemars::schema_for!(T)
+            .schema
+            .object
+            .expect("schema is an object")
+            .properties
+            .into_keys()
+            .collect()
+    }
+
+    fn keys(keys: &[&str]) -> BTreeSet<String> {
+        keys.iter().map(|key| (*key).to_owned()).collect()
+    }
+
+    #[test]
+    fn test_config_file_keys() {
+        let mut expected = keys(ConfigFile::KEYS);
+        // Tables keyed by hostname aren't configuration keys.
+        expected.insert("host".to_owned());
+        assert_eq!(schema_keys::<ConfigFile>(), expected);
+    }
+
+    #[test]
+    fn test_host_config_keys() {
+        assert_eq!(schema_keys::<HostConfig>(), keys(HostConfig::KEYS));
+    }
+
+    #[test]
+    fn test_gc_config_keys() {
+        assert_eq!(schema_keys::<GcConfig>(), keys(GcConfig::KEYS));
+    }
+}
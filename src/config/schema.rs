@@ -0,0 +1,69 @@
+use miette::IntoDiagnostic;
+use schemars::gen::SchemaSettings;
+
+use super::ConfigFile;
+
+/// Generate a JSON Schema for `config.toml`.
+pub fn config_schema() -> miette::Result<String> {
+    // TOML has no `null`, so optional keys are just omitted.
+    let schema = SchemaSettings::draft07()
+        .with(|settings| {
+            settings.option_nullable = false;
+            settings.option_add_null_type = false;
+        })
+        .into_generator()
+        .into_root_schema_for::<ConfigFile>();
+    let mut ret = serde_json::to_string_pretty(&schema).into_diagnostic()?;
+    ret.push('\n');
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_schema_up_to_date() {
+        let expected = include_str!("../../schema/config.schema.json");
+        let actual = config_schema().unwrap();
+        assert!(
+            expected == actual,
+            "`schema/config.schema.json` is out of date; regenerate it with:\n    cargo run -- config schema > schema/config.schema.json"
+        );
+    }
+
+    fn validate(config: &str) -> Result<(), String> {
+        let schema: serde_json::Value =
+            serde_json::from_str(include_str!("../../schema/config.schema.json")).unwrap();
+        let config: serde_json::Value = toml::from_str(config).unwrap();
+        jsonschema::validate(&schema, &config).map_err(|err| err.to_string())
+    }
+
+    #[test]
+    fn test_config_schema_validates_config() {
+        validate(
+            r#"
+            update = true
+            update-inputs = ["nixpkgs"]
+            profile-mode = "strict"
+
+            [gc]
+            after-switch = true
+            older-than = "30d"
+            keep-generations = 5
+
+            [host.grandiflora]
+            profile = "/nix/var/nix/profiles/per-user/wiggles/profile"
+            gc = { older-than = "7d" }
+            "#,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_config_schema_rejects_invalid_config() {
+        assert!(validate("[gc]\nolder-than = 30").is_err());
+        assert!(validate("[gc]\nolder-than = \"1w\"").is_err());
+        assert!(validate("updat = true").is_err());
+    }
+}
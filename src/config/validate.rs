@@ -0,0 +1,225 @@
+use std::ops::Range;
+
+use camino::Utf8Path;
+use miette::miette;
+use miette::IntoDiagnostic;
+use miette::LabeledSpan;
+use miette::NamedSource;
+use serde_path_to_error::Segment;
+use toml_edit::ImDocument;
+use toml_edit::Item;
+use toml_edit::TableLike;
+
+use super::layers::normalize_key;
+use super::layers::normalize_keys;
+use super::ConfigFile;
+use super::GcConfig;
+use super::HostConfig;
+
+/// Check a configuration file for syntax errors, unknown keys, and invalid values, reporting
+/// errors with the location in the file.
+pub fn validate_file(path: &Utf8Path, contents: &str) -> miette::Result<()> {
+    let source = || NamedSource::new(path, contents.to_owned());
+
+    let document = ImDocument::parse(contents)
+        .map_err(|err| spanned_error(err.message(), err.span(), None).with_source_code(source()))?;
+
+    check_config_keys(document.as_table(), ConfigFile::KEYS, &["host"], "")
+        .map_err(|err| err.with_source_code(source()))?;
+
+    // Check the values with keys normalized the same way they are when the configuration is
+    // loaded, then find the invalid value in the original document.
+    let mut table = contents.parse::<toml::Table>().into_diagnostic()?;
+    normalize_keys(&mut table);
+    serde_path_to_error::deserialize::<_, ConfigFile>(toml::Value::Table(table)).map_err(
+        |err| {
+            let span = value_span(document.as_item(), err.path());
+            spanned_error(err.inner().message(), span, None).with_source_code(source())
+        },
+    )?;
+
+    Ok(())
+}
+
+/// Check the keys of a configuration file or `[host.<name>]` table, including in nested
+/// tables like `[gc]`.
+fn check_config_keys(
+    table: &dyn TableLike,
+    keys: &[&str],
+    tables: &[&str],
+    prefix: &str,
+) -> miette::Result<()> {
+    check_keys(table, keys, tables, prefix)?;
+
+    for (key, item) in table.iter() {
+        let Some(nested) = item.as_table_like() else {
+            continue;
+        };
+        let nested_prefix = format!("{prefix}{key}.");
+        match normalize_key(key).as_str() {
+            "gc" => check_keys(nested, GcConfig::KEYS, &[], &nested_prefix)?,
+            "host" if tables.contains(&"host") => {
+                for (name, host) in nested.iter() {
+                    if let Some(host) = host.as_table_like() {
+                        check_config_keys(
+                            host,
+                            HostConfig::KEYS,
+                            &[],
+                            &format!("{nested_prefix}{name}."),
+                        )?;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that every key in `table` is in `keys` or `tables`, suggesting similar keys if not.
+fn check_keys(
+    table: &dyn TableLike,
+    keys: &[&str],
+    tables: &[&str],
+    prefix: &str,
+) -> miette::Result<()> {
+    let known = || keys.iter().chain(tables);
+
+    for (key, _) in table.iter() {
+        let normalized = normalize_key(key);
+        if known().any(|known| *known == normalized) {
+            continue;
+        }
+
+        let span = table.key(key).and_then(|key| key.span());
+        let suggestion = known()
+            .map(|known| (strsim::jaro_winkler(&normalized, known), known))
+            .filter(|(score, _)| *score > 0.8)
+            .max_by(|(a, _), (b, _)| a.total_cmp(b))
+            .map(|(_, known)| format!("did you mean `{prefix}{known}`?"));
+
+        return Err(spanned_error(
+            &format!("Unknown configuration key `{prefix}{key}`"),
+            span,
+            suggestion,
+        ));
+    }
+
+    Ok(())
+}
+
+/// Find the span of the value at `path` (with normalized keys) in the original document, or
+/// of the closest enclosing value.
+fn value_span(document: &Item, path: &serde_path_to_error::Path) -> Option<Range<usize>> {
+    let mut item = document;
+    let mut span = None;
+    let mut previous: Option<&str> = None;
+    for segment in path.iter() {
+        match segment {
+            Segment::Map { key } => {
+                let table = item.as_table_like()?;
+                // Hostnames aren't normalized.
+                let is_hostname = previous == Some("host");
+                let found = table.iter().find(|(existing, _)| {
+                    if is_hostname {
+                        existing == key
+                    } else {
+                        normalize_key(existing) == *key
+                    }
+                });
+                let Some((_, found)) = found else {
+                    break;
+                };
+                item = found;
+                span = item.span().or(span);
+                previous = Some(key);
+            }
+            Segment::Seq { index } => {
+                return item
+                    .as_array()
+                    .and_then(|array| array.get(*index))
+                    .and_then(|value| value.span())
+                    .or(span);
+            }
+            Segment::Enum { .. } | Segment::Unknown => break,
+        }
+    }
+    span
+}
+
+fn spanned_error(
+    message: &str,
+    span: Option<Range<usize>>,
+    help: Option<String>,
+) -> miette::Report {
+    let labels = span
+        .map(|span| vec![LabeledSpan::at(span, "here")])
+        .unwrap_or_default();
+
+    match help {
+        Some(help) => miette!(labels = labels, help = help, "{}", message.trim()),
+        None => miette!(labels = labels, "{}", message.trim()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validate(contents: &str) -> Result<(), String> {
+        validate_file(Utf8Path::new("config.toml"), contents).map_err(|err| {
+            let help = err.help().map(|help| help.to_string()).unwrap_or_default();
+            format!("{err}; {help}")
+        })
+    }
+
+    #[test]
+    fn test_validate_snake_case_keys() {
+        validate(
+            r#"
+            log_filters = "debug"
+            [gc]
+            keep_generations = 3
+            [host.my_box]
+            use_path_flake = true
+            "#,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_validate_unknown_key() {
+        assert_eq!(
+            validate("flak = \"/flake\"").unwrap_err(),
+            "Unknown configuration key `flak`; did you mean `flake`?"
+        );
+    }
+
+    #[test]
+    fn test_validate_unknown_nested_key() {
+        assert_eq!(
+            validate("[gc]\nkeep-generation = 3").unwrap_err(),
+            "Unknown configuration key `gc.keep-generation`; did you mean `gc.keep-generations`?"
+        );
+        assert_eq!(
+            validate("host.box = { flak = \"/flake\" }").unwrap_err(),
+            "Unknown configuration key `host.box.flak`; did you mean `host.box.flake`?"
+        );
+        assert_eq!(
+            validate("[host.box.gc]\nolder_then = \"30d\"").unwrap_err(),
+            "Unknown configuration key `host.box.gc.older_then`; did you mean `host.box.gc.older-than`?"
+        );
+    }
+
+    #[test]
+    fn test_validate_value_span() {
+        let contents = "[host.my_box]\nuse_path_flake = \"yes\"\n";
+        let err = validate_file(Utf8Path::new("config.toml"), contents).unwrap_err();
+        let label = err.labels().unwrap().next().unwrap();
+        assert_eq!(
+            &contents[label.offset()..label.offset() + label.len()],
+            "\"yes\""
+        );
+    }
+}
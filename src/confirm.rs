@@ -0,0 +1,23 @@
+use std::io::IsTerminal;
+use std::io::Write;
+
+use miette::miette;
+use miette::IntoDiagnostic;
+
+/// Ask a yes/no question on the terminal. Defaults to no.
+///
+/// Fails if stdin isn't a terminal, so unattended runs never guess.
+pub fn confirm(question: &str) -> miette::Result<bool> {
+    if !std::io::stdin().is_terminal() {
+        return Err(miette!(
+            help = "Pass `--yes` to skip confirmation",
+            "Cannot ask for confirmation because stdin is not a terminal: {question}"
+        ));
+    }
+
+    eprint!("{question} [y/N] ");
+    std::io::stderr().flush().into_diagnostic()?;
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line).into_diagnostic()?;
+    Ok(matches!(line.trim(), "y" | "Y" | "yes" | "Yes"))
+}
@@ -0,0 +1,400 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+
+use camino::Utf8Path;
+use camino::Utf8PathBuf;
+use miette::miette;
+use walkdir::WalkDir;
+
+use crate::format_bulleted_list;
+use crate::nix::ProfileListV3Element;
+use crate::symlinks::link_target;
+
+/// A file provided by both the new packages and another profile element.
+struct FileConflict<'a> {
+    /// Relative to the profile root, like `bin/foo`.
+    path: Utf8PathBuf,
+    element: &'a str,
+    source: String,
+}
+
+/// Check that installing `out_paths` with `priority` won't conflict with files from the
+/// `others` elements.
+///
+/// `nix profile` refuses to install a package that provides the same file as another element
+/// with the same priority, unless both resolve to the same store path.
+#[tracing::instrument(level = "debug", skip_all)]
+pub fn check_conflicts(
+    out_paths: &BTreeSet<Utf8PathBuf>,
+    priority: u16,
+    others: &[(String, &ProfileListV3Element)],
+) -> miette::Result<()> {
+    let mut new_files = BTreeMap::new();
+    for out_path in out_paths {
+        profile_files(out_path, &mut new_files);
+    }
+
+    let mut conflicts = Vec::new();
+    for (name, element) in others {
+        if !element.active {
+            continue;
+        }
+
+        let mut files = BTreeMap::new();
+        for store_path in &element.store_paths {
+            profile_files(store_path, &mut files);
+        }
+
+        for (path, target) in files {
+            match new_files.get(&path) {
+                Some(new_target) if *new_target != target => {
+                    if element.priority == priority {
+                        conflicts.push(FileConflict {
+                            path,
+                            element: name,
+                            source: element.source(),
+                        });
+                    } else {
+                        let winner = if priority < element.priority {
+                            "home-mangler"
+                        } else {
+                            name
+                        };
+                        tracing::debug!(%path, element = name, "File provided by both; {winner} takes precedence");
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    if conflicts.is_empty() {
+        return Ok(());
+    }
+
+    Err(miette!(
+        help = format!(
+            "Set `priority` in your configuration to a number lower than {priority} to prefer \
+             home-mangler's packages, or higher to prefer the other packages.\n\
+             Or, remove the other packages with `home-mangler adopt --remove`"
+        ),
+        "The new packages conflict with other packages in `nix profile` with the same priority \
+         ({priority}):\n{}",
+        format_bulleted_list(conflicts.iter().map(|conflict| format!(
+            "{} is also provided by {}: {}",
+            conflict.path, conflict.element, conflict.source
+        )))
+    ))
+}
+
+/// Check for files shadowed inside the `symlinkJoin` built by `makePackages`.
+///
+/// `symlinkJoin` links files from `inputs` in order and silently keeps the first one when
+/// several inputs provide the same path. Shadowed files are reported as warnings; this only
+/// fails if `deny_bin` is set and files in `bin/` are shadowed.
+#[tracing::instrument(level = "debug", skip_all)]
+pub fn check_shadowed(inputs: &[Utf8PathBuf], deny_bin: bool) -> miette::Result<()> {
+    // Map from relative paths to the input providing them and what they resolve to.
+    let mut winners: BTreeMap<Utf8PathBuf, (&Utf8Path, Utf8PathBuf)> = BTreeMap::new();
+    let mut shadowed = Vec::new();
+    let mut shadows_bin = false;
+    for input in inputs {
+        let mut files = BTreeMap::new();
+        profile_files(input, &mut files);
+        for (path, target) in files {
+            match winners.get(&path) {
+                Some((winner, winner_target)) => {
+                    if *winner_target != target {
+                        shadowed.push(format!("{path}: {winner} shadows {input}"));
+                        shadows_bin |= path.starts_with("bin");
+                    }
+                }
+                None => {
+                    winners.insert(path, (input.as_path(), target));
+                }
+            }
+        }
+    }
+
+    if shadowed.is_empty() {
+        return Ok(());
+    }
+
+    if deny_bin && shadows_bin {
+        return Err(miette!(
+            help =
+                "Remove one of the packages from your configuration, or unset `deny-bin-conflicts`",
+            "Several packages provide the same files, including executables in `bin/`:\n{}",
+            format_bulleted_list(&shadowed)
+        ));
+    }
+
+    tracing::warn!(
+        "Several packages provide the same files; only the first is installed:\n{}",
+        format_bulleted_list(&shadowed)
+    );
+    Ok(())
+}
+
+/// Collect the files a store path adds to a profile, mapped from their path relative to the
+/// profile root to the file a profile symlink would point to.
+///
+/// Like `buildEnv`, this doesn't follow symlinks while walking, so it stays inside the store
+/// path. Symlinks to directories are merged by `buildEnv` rather than conflicting, so they're
+/// skipped. Entries that can't be read are skipped with a warning.
+fn profile_files(base: &Utf8Path, files: &mut BTreeMap<Utf8PathBuf, Utf8PathBuf>) {
+    for entry in WalkDir::new(base) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                tracing::warn!("Skipping unreadable entry in {base}: {err}");
+                continue;
+            }
+        };
+        if entry.file_type().is_dir() || entry.depth() == 0 {
+            continue;
+        }
+
+        let Some(path) = Utf8Path::from_path(entry.path()) else {
+            tracing::warn!("Skipping non-UTF-8 path: {}", entry.path().display());
+            continue;
+        };
+        let Ok(relative) = path.strip_prefix(base) else {
+            continue;
+        };
+
+        let target = link_target(path);
+        if entry.file_type().is_symlink() && target.is_dir() {
+            continue;
+        }
+        files.insert(relative.to_owned(), target);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::Permissions;
+    use std::os::unix::fs::symlink;
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    /// Create a fake store path `name` in `dir` containing `files`.
+    fn store_path(dir: &Utf8Path, name: &str, files: &[&str]) -> Utf8PathBuf {
+        let path = dir.join(name);
+        for file in files {
+            let file = path.join(file);
+            std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+            std::fs::write(&file, name).unwrap();
+        }
+        path
+    }
+
+    fn element(priority: u16, store_paths: &[&Utf8Path]) -> ProfileListV3Element {
+        serde_json::from_value(serde_json::json!({
+            "priority": priority,
+            "storePaths": store_paths,
+            "originalUrl": "github:foo/tool",
+            "attrPath": "packages.x86_64-linux.default",
+        }))
+        .unwrap()
+    }
+
+    fn temp_dir() -> (tempfile::TempDir, Utf8PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = Utf8Path::from_path(dir.path()).unwrap().to_owned();
+        (dir, path)
+    }
+
+    #[test]
+    fn test_check_conflicts_same_priority() {
+        let (_dir, dir) = temp_dir();
+        let new = store_path(&dir, "new", &["bin/foo", "bin/bar"]);
+        let other = store_path(&dir, "other", &["bin/foo", "bin/baz"]);
+        let other = element(5, &[&other]);
+
+        let err =
+            check_conflicts(&BTreeSet::from([new]), 5, &[("tool".to_owned(), &other)]).unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("same priority (5)"), "{message}");
+        assert!(
+            message.contains("bin/foo is also provided by tool"),
+            "{message}"
+        );
+        assert!(!message.contains("bin/bar"), "{message}");
+        assert!(!message.contains("bin/baz"), "{message}");
+        let help = err.help().unwrap().to_string();
+        assert!(help.contains("lower than 5"), "{help}");
+    }
+
+    #[test]
+    fn test_check_conflicts_different_priority() {
+        let (_dir, dir) = temp_dir();
+        let new = store_path(&dir, "new", &["bin/foo"]);
+        let other = store_path(&dir, "other", &["bin/foo"]);
+        let other = element(6, &[&other]);
+
+        check_conflicts(&BTreeSet::from([new]), 5, &[("tool".to_owned(), &other)]).unwrap();
+    }
+
+    #[test]
+    fn test_check_conflicts_same_target() {
+        let (_dir, dir) = temp_dir();
+        let shared = store_path(&dir, "shared", &["bin/foo"]);
+        let new = dir.join("new/bin");
+        let other = dir.join("other/bin");
+        for path in [&new, &other] {
+            std::fs::create_dir_all(path).unwrap();
+            symlink(shared.join("bin/foo"), path.join("foo")).unwrap();
+        }
+        let other = element(5, &[other.parent().unwrap()]);
+
+        check_conflicts(
+            &BTreeSet::from([new.parent().unwrap().to_owned()]),
+            5,
+            &[("tool".to_owned(), &other)],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_check_conflicts_inactive() {
+        let (_dir, dir) = temp_dir();
+        let new = store_path(&dir, "new", &["bin/foo"]);
+        let other = store_path(&dir, "other", &["bin/foo"]);
+        let mut other = element(5, &[&other]);
+        other.active = false;
+
+        check_conflicts(&BTreeSet::from([new]), 5, &[("tool".to_owned(), &other)]).unwrap();
+    }
+
+    #[test]
+    fn test_profile_files() {
+        let (_dir, dir) = temp_dir();
+        let shared = store_path(&dir, "shared", &["share/doc/README", "bin/tool"]);
+        let package = store_path(&dir, "package", &["bin/foo", "share/man/man1/foo.1"]);
+        // Symlinks to files are followed to their target.
+        symlink(shared.join("bin/tool"), package.join("bin/tool")).unwrap();
+        // Symlinks to directories are merged by `buildEnv`, so they're skipped.
+        symlink(shared.join("share/doc"), package.join("share/doc")).unwrap();
+        // Dangling symlinks resolve to their target.
+        symlink("missing", package.join("bin/dangling")).unwrap();
+
+        let mut files = BTreeMap::new();
+        profile_files(&package, &mut files);
+        assert_eq!(
+            files,
+            BTreeMap::from([
+                ("bin/dangling".into(), package.join("bin/missing")),
+                ("bin/foo".into(), package.join("bin/foo")),
+                ("bin/tool".into(), shared.join("bin/tool")),
+                (
+                    "share/man/man1/foo.1".into(),
+                    package.join("share/man/man1/foo.1")
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_profile_files_unreadable() {
+        let (_dir, dir) = temp_dir();
+        let package = store_path(&dir, "package", &["bin/foo", "secret/file"]);
+        let secret = package.join("secret");
+        std::fs::set_permissions(&secret, Permissions::from_mode(0o000)).unwrap();
+
+        let mut files = BTreeMap::new();
+        profile_files(&package, &mut files);
+        // `root` can read the directory anyway.
+        assert!(files.contains_key(Utf8Path::new("bin/foo")));
+
+        let mut files = BTreeMap::new();
+        profile_files(&dir.join("missing"), &mut files);
+        assert!(files.is_empty());
+
+        std::fs::set_permissions(&secret, Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[test]
+    fn test_check_shadowed_first_input_wins() {
+        let (_dir, dir) = temp_dir();
+        let first = store_path(&dir, "first", &["share/man/foo.1"]);
+        let second = store_path(&dir, "second", &["share/man/foo.1"]);
+
+        check_shadowed(&[first, second], true).unwrap();
+
+        let err = check_shadowed(
+            &[
+                store_path(&dir, "third", &["bin/foo"]),
+                store_path(&dir, "fourth", &["bin/foo"]),
+            ],
+            true,
+        )
+        .unwrap_err();
+        let message = err.to_string();
+        assert!(
+            message.contains(&format!(
+                "bin/foo: {} shadows {}",
+                dir.join("third"),
+                dir.join("fourth")
+            )),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn test_check_shadowed_same_target() {
+        let (_dir, dir) = temp_dir();
+        let shared = store_path(&dir, "shared", &["bin/foo"]);
+        let inputs = ["first", "second"].map(|name| {
+            let path = dir.join(name);
+            std::fs::create_dir_all(path.join("bin")).unwrap();
+            symlink(shared.join("bin/foo"), path.join("bin/foo")).unwrap();
+            path
+        });
+
+        check_shadowed(&inputs, true).unwrap();
+    }
+
+    #[test]
+    fn test_check_shadowed_directory_symlinks() {
+        let (_dir, dir) = temp_dir();
+        let shared = store_path(&dir, "shared", &["bin/foo"]);
+        let first = store_path(&dir, "first", &["share/doc"]);
+        let second = dir.join("second");
+        std::fs::create_dir_all(&second).unwrap();
+        // Merged into `bin/` rather than shadowing it.
+        symlink(shared.join("bin"), second.join("bin")).unwrap();
+        let third = store_path(&dir, "third", &["bin/bar"]);
+
+        check_shadowed(&[first, second, third], true).unwrap();
+    }
+
+    #[test]
+    fn test_check_shadowed_deny_bin_conflicts() {
+        let (_dir, dir) = temp_dir();
+        let inputs = [
+            store_path(&dir, "first", &["bin/foo", "share/man/foo.1"]),
+            store_path(&dir, "second", &["bin/foo", "share/man/foo.1"]),
+        ];
+        // Only a warning by default.
+        check_shadowed(&inputs, false).unwrap();
+        let message = check_shadowed(&inputs, true).unwrap_err().to_string();
+        assert!(message.contains("executables in `bin/`"), "{message}");
+        assert!(message.contains("share/man/foo.1"), "{message}");
+
+        // Files outside `bin/` never fail.
+        let inputs = [
+            store_path(&dir, "third", &["share/man/foo.1"]),
+            store_path(&dir, "fourth", &["share/man/foo.1"]),
+        ];
+        check_shadowed(&inputs, true).unwrap();
+
+        // `bin` must be the first path component.
+        let inputs = [
+            store_path(&dir, "fifth", &["share/bin/foo"]),
+            store_path(&dir, "sixth", &["share/bin/foo"]),
+        ];
+        check_shadowed(&inputs, true).unwrap();
+    }
+}
@@ -13,6 +13,7 @@ use owo_colors::Stream;
 use tap::TryConv;
 use walkdir::WalkDir;
 
+#[tracing::instrument(level = "debug", skip_all)]
 pub fn diff_trees(
     removed_paths: &BTreeSet<&Utf8Path>,
     added_paths: &BTreeSet<&Utf8Path>,
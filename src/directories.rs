@@ -79,6 +79,27 @@ impl ProjectPaths {
         Ok(ret)
     }
 
+    /// System-wide configuration files, most important first.
+    ///
+    /// These are `home-mangler/config.toml` in each of `$XDG_CONFIG_DIRS`, which defaults to
+    /// `/etc/xdg`.
+    pub fn system_config_paths(&self) -> Vec<Utf8PathBuf> {
+        let dirs = std::env::var("XDG_CONFIG_DIRS")
+            .ok()
+            .filter(|dirs| !dirs.is_empty())
+            .unwrap_or_else(|| "/etc/xdg".to_owned());
+
+        dirs.split(':')
+            .filter(|dir| !dir.is_empty())
+            .map(|dir| {
+                let mut path = Utf8PathBuf::from(dir);
+                path.push("home-mangler");
+                path.push("config.toml");
+                path
+            })
+            .collect()
+    }
+
     pub fn flake_paths(&self) -> miette::Result<Vec<Utf8PathBuf>> {
         let mut ret = self.config_dirs()?;
 
@@ -88,4 +109,43 @@ impl ProjectPaths {
 
         Ok(ret)
     }
+
+    /// Directory for persistent state, like the switch history.
+    ///
+    /// This is `$XDG_STATE_HOME/home-mangler` on Linux. Other platforms don't have a state
+    /// directory, so we use the local data directory instead.
+    pub fn state_dir(&self) -> miette::Result<Utf8PathBuf> {
+        self.project_dirs
+            .state_dir()
+            .unwrap_or_else(|| self.project_dirs.data_local_dir())
+            .to_path_buf()
+            .try_conv::<Utf8PathBuf>()
+            .into_diagnostic()
+    }
+
+    pub fn log_dir(&self) -> miette::Result<Utf8PathBuf> {
+        let mut ret = self.state_dir()?;
+        ret.push("logs");
+        Ok(ret)
+    }
+
+    /// Directory for the output of `nix` commands.
+    pub fn nix_log_dir(&self) -> miette::Result<Utf8PathBuf> {
+        let mut ret = self.log_dir()?;
+        ret.push("nix");
+        Ok(ret)
+    }
+
+    /// Directory for garbage collector roots for built packages.
+    pub fn gc_roots_dir(&self) -> miette::Result<Utf8PathBuf> {
+        let mut ret = self.state_dir()?;
+        ret.push("gcroots");
+        Ok(ret)
+    }
+
+    pub fn history_path(&self) -> miette::Result<Utf8PathBuf> {
+        let mut ret = self.state_dir()?;
+        ret.push("history.jsonl");
+        Ok(ret)
+    }
 }
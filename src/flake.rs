@@ -68,6 +68,16 @@ impl Display for PathFlake {
 }
 
 impl PathFlake {
+    /// The directory containing the `flake.nix`, with symlinks resolved.
+    pub fn path(&self) -> &Utf8Path {
+        &self.path
+    }
+
+    /// Is this flake referred to with a `path:` URL?
+    pub fn use_path_flake(&self) -> bool {
+        self.use_path_flake
+    }
+
     /// We resolve symlinks to work around Nix.
     /// See: <https://github.com/NixOS/nix/issues/9253>
     fn new(path: &Utf8Path) -> miette::Result<Self> {
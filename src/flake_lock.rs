@@ -0,0 +1,405 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::fmt::Display;
+
+use miette::miette;
+use owo_colors::OwoColorize;
+use owo_colors::Stream;
+
+use crate::format_bulleted_list;
+
+/// A parsed `flake.lock` file.
+///
+/// See: <https://nix.dev/manual/nix/latest/command-ref/new-cli/nix3-flake#lock-files>
+#[derive(serde::Deserialize, Debug, Clone, Default)]
+pub struct FlakeLock {
+    nodes: BTreeMap<String, LockNode>,
+    root: String,
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+struct LockNode {
+    #[serde(default)]
+    inputs: BTreeMap<String, LockInput>,
+    locked: Option<LockedRef>,
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(untagged)]
+enum LockInput {
+    /// The name of a node in the lockfile.
+    Node(String),
+    /// A `follows` path, like `["home-mangler", "nixpkgs"]`.
+    ///
+    /// These are aliases for other inputs, so we don't report changes to them separately.
+    Follows(#[allow(dead_code)] Vec<String>),
+}
+
+/// The `locked` attribute of a lockfile node.
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct LockedRef {
+    /// `github`, `git`, `path`, etc.
+    #[serde(rename = "type")]
+    kind: String,
+    owner: Option<String>,
+    repo: Option<String>,
+    rev: Option<String>,
+    /// Unix timestamp.
+    last_modified: Option<i64>,
+}
+
+impl FlakeLock {
+    /// The names of the flake's direct inputs.
+    pub fn root_inputs(&self) -> impl Iterator<Item = &str> {
+        self.nodes
+            .get(&self.root)
+            .into_iter()
+            .flat_map(|node| node.inputs.keys().map(|name| name.as_str()))
+    }
+
+    /// Check that each of the given names is a direct input of the flake.
+    pub fn validate_root_inputs(&self, names: &[String]) -> miette::Result<()> {
+        let root_inputs = self.root_inputs().collect::<BTreeSet<_>>();
+        let unknown = names
+            .iter()
+            .filter(|name| !root_inputs.contains(name.as_str()))
+            .collect::<Vec<_>>();
+
+        if unknown.is_empty() {
+            Ok(())
+        } else {
+            Err(miette!(
+                "Unknown flake inputs:\n{}\nThe flake has these inputs:\n{}",
+                format_bulleted_list(unknown),
+                format_bulleted_list(root_inputs)
+            ))
+        }
+    }
+
+    /// Map from input paths (like `home-mangler/nixpkgs`) to their locked references.
+    fn locked_inputs(&self) -> BTreeMap<String, &LockedRef> {
+        let mut ret = BTreeMap::new();
+        let mut queue = Vec::new();
+        if self.nodes.contains_key(&self.root) {
+            queue.push((String::new(), &self.root));
+        }
+
+        while let Some((prefix, node_name)) = queue.pop() {
+            let node = match self.nodes.get(node_name) {
+                Some(node) => node,
+                None => {
+                    tracing::debug!(node = node_name, "Lockfile references missing node");
+                    continue;
+                }
+            };
+
+            for (input_name, input) in &node.inputs {
+                let node_name = match input {
+                    LockInput::Node(node_name) => node_name,
+                    LockInput::Follows(_) => continue,
+                };
+
+                let path = if prefix.is_empty() {
+                    input_name.clone()
+                } else {
+                    format!("{prefix}/{input_name}")
+                };
+
+                if let Some(locked) = self.nodes.get(node_name).and_then(|n| n.locked.as_ref()) {
+                    ret.insert(path.clone(), locked);
+                }
+                queue.push((path, node_name));
+            }
+        }
+
+        ret
+    }
+
+    /// Find the inputs whose revision or modification time differ between `self` and `new`.
+    pub fn diff(&self, new: &FlakeLock) -> Vec<InputChange> {
+        let old_inputs = self.locked_inputs();
+        let new_inputs = new.locked_inputs();
+        let mut ret = Vec::new();
+
+        for (input, old) in &old_inputs {
+            match new_inputs.get(input) {
+                Some(new) => {
+                    if old.rev != new.rev || old.last_modified != new.last_modified {
+                        ret.push(InputChange::new(input, Some(old), Some(new)));
+                    }
+                }
+                None => ret.push(InputChange::new(input, Some(old), None)),
+            }
+        }
+
+        for (input, new) in &new_inputs {
+            if !old_inputs.contains_key(input) {
+                ret.push(InputChange::new(input, None, Some(new)));
+            }
+        }
+
+        ret.sort_by(|a, b| a.input.cmp(&b.input));
+        ret
+    }
+}
+
+/// A change to a single input in a `flake.lock` file.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct InputChange {
+    /// The path to the input, like `nixpkgs` or `home-mangler/nixpkgs`.
+    pub input: String,
+    /// `None` if the input was added.
+    pub old: Option<InputRevision>,
+    /// `None` if the input was removed.
+    pub new: Option<InputRevision>,
+    /// For GitHub inputs, a URL comparing the old and new revisions.
+    pub compare_url: Option<String>,
+}
+
+impl InputChange {
+    fn new(input: &str, old: Option<&LockedRef>, new: Option<&LockedRef>) -> Self {
+        let compare_url = match (old, new) {
+            (Some(old), Some(new)) => github_compare_url(old, new),
+            _ => None,
+        };
+
+        Self {
+            input: input.to_owned(),
+            old: old.map(InputRevision::from),
+            new: new.map(InputRevision::from),
+            compare_url,
+        }
+    }
+}
+
+fn github_compare_url(old: &LockedRef, new: &LockedRef) -> Option<String> {
+    if old.kind != "github" || new.kind != "github" {
+        return None;
+    }
+    let owner = new.owner.as_deref()?;
+    let repo = new.repo.as_deref()?;
+    if old.owner.as_deref() != Some(owner) || old.repo.as_deref() != Some(repo) {
+        return None;
+    }
+    let old_rev = old.rev.as_deref()?;
+    let new_rev = new.rev.as_deref()?;
+    Some(format!(
+        "https://github.com/{owner}/{repo}/compare/{old_rev}...{new_rev}"
+    ))
+}
+
+/// A locked revision of an input.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct InputRevision {
+    pub rev: Option<String>,
+    pub last_modified: Option<i64>,
+}
+
+impl From<&LockedRef> for InputRevision {
+    fn from(locked: &LockedRef) -> Self {
+        Self {
+            rev: locked.rev.clone(),
+            last_modified: locked.last_modified,
+        }
+    }
+}
+
+impl InputRevision {
+    pub fn short_rev(&self) -> Option<&str> {
+        self.rev.as_deref().map(|rev| rev.get(..7).unwrap_or(rev))
+    }
+
+    /// The modification date, formatted like `2023-11-04`.
+    pub fn date(&self) -> Option<String> {
+        let timestamp = jiff::Timestamp::from_second(self.last_modified?).ok()?;
+        Some(timestamp.strftime("%Y-%m-%d").to_string())
+    }
+}
+
+impl Display for InputRevision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.short_rev(), self.date()) {
+            (Some(rev), Some(date)) => write!(f, "{rev} ({date})"),
+            (Some(rev), None) => write!(f, "{rev}"),
+            (None, Some(date)) => write!(f, "{date}"),
+            (None, None) => write!(f, "unknown"),
+        }
+    }
+}
+
+/// Format a table of input changes, one per line.
+pub fn format_input_changes(changes: &[InputChange]) -> String {
+    let rows = changes
+        .iter()
+        .map(|change| {
+            (
+                change.input.as_str(),
+                change
+                    .old
+                    .as_ref()
+                    .map(|rev| rev.to_string())
+                    .unwrap_or_else(|| "(added)".to_owned()),
+                change
+                    .new
+                    .as_ref()
+                    .map(|rev| rev.to_string())
+                    .unwrap_or_else(|| "(removed)".to_owned()),
+                change.compare_url.as_deref(),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let input_width = rows.iter().map(|row| row.0.len()).max().unwrap_or(0);
+    let old_width = rows.iter().map(|row| row.1.len()).max().unwrap_or(0);
+    let new_width = rows.iter().map(|row| row.2.len()).max().unwrap_or(0);
+
+    let mut ret = String::new();
+    for (input, old, new, compare_url) in rows {
+        ret.push_str(&format!(
+            "{input:input_width$}  {}  →  ",
+            format!("{old:old_width$}").if_supports_color(Stream::Stdout, |text| text.red()),
+        ));
+        match compare_url {
+            Some(url) => {
+                ret.push_str(
+                    &format!("{new:new_width$}")
+                        .if_supports_color(Stream::Stdout, |text| text.green())
+                        .to_string(),
+                );
+                ret.push_str("  ");
+                ret.push_str(url);
+            }
+            None => {
+                ret.push_str(
+                    &new.if_supports_color(Stream::Stdout, |text| text.green())
+                        .to_string(),
+                );
+            }
+        }
+        ret.push('\n');
+    }
+
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A lockfile where `nixpkgs` is locked to `nixpkgs_rev`, with additional root inputs
+    /// locked to `extra_inputs`.
+    fn lock(nixpkgs_rev: &str, nixpkgs_modified: i64, extra_inputs: &[(&str, &str)]) -> FlakeLock {
+        let mut nodes = String::new();
+        let mut inputs = String::new();
+        for (name, locked) in extra_inputs {
+            nodes.push_str(&format!(r#""{name}": {{ "locked": {locked} }},"#));
+            inputs.push_str(&format!(r#""{name}": "{name}","#));
+        }
+        serde_json::from_str(&format!(
+            r#"{{
+                "nodes": {{
+                    "home-mangler": {{
+                        "inputs": {{ "nixpkgs": ["nixpkgs"] }},
+                        "locked": {{
+                            "type": "github",
+                            "owner": "9999years",
+                            "repo": "home-mangler",
+                            "rev": "1111111111111111111111111111111111111111",
+                            "lastModified": 1700000000
+                        }}
+                    }},
+                    "nixpkgs": {{
+                        "locked": {{
+                            "type": "github",
+                            "owner": "NixOS",
+                            "repo": "nixpkgs",
+                            "rev": "{nixpkgs_rev}",
+                            "lastModified": {nixpkgs_modified}
+                        }}
+                    }},
+                    {nodes}
+                    "root": {{
+                        "inputs": {{
+                            {inputs}
+                            "home-mangler": "home-mangler",
+                            "nixpkgs": "nixpkgs"
+                        }}
+                    }}
+                }},
+                "root": "root",
+                "version": 7
+            }}"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_root_inputs() {
+        let lock = lock("a", 1, &[]);
+        assert_eq!(
+            lock.root_inputs().collect::<Vec<_>>(),
+            vec!["home-mangler", "nixpkgs"]
+        );
+        assert!(lock.validate_root_inputs(&["nixpkgs".to_owned()]).is_ok());
+        assert!(lock
+            .validate_root_inputs(&["flake-utils".to_owned()])
+            .is_err());
+    }
+
+    #[test]
+    fn test_diff_unchanged() {
+        let old = lock("aaaaaaaaaa", 1700000000, &[]);
+        assert!(old.diff(&old.clone()).is_empty());
+    }
+
+    #[test]
+    fn test_diff_changed() {
+        let old = lock("aaaaaaaaaa", 1699056000, &[]);
+        let new = lock("bbbbbbbbbb", 1699142400, &[]);
+        let changes = old.diff(&new);
+
+        // `home-mangler/nixpkgs` follows `nixpkgs`, so it isn't reported separately.
+        assert_eq!(changes.len(), 1);
+        let change = &changes[0];
+        assert_eq!(change.input, "nixpkgs");
+        assert_eq!(
+            change.old.as_ref().unwrap().to_string(),
+            "aaaaaaa (2023-11-04)"
+        );
+        assert_eq!(
+            change.new.as_ref().unwrap().to_string(),
+            "bbbbbbb (2023-11-05)"
+        );
+        assert_eq!(
+            change.compare_url.as_deref(),
+            Some("https://github.com/NixOS/nixpkgs/compare/aaaaaaaaaa...bbbbbbbbbb")
+        );
+    }
+
+    #[test]
+    fn test_diff_added_and_removed() {
+        let old = lock(
+            "a",
+            1,
+            &[("flake-utils", r#"{ "type": "github", "rev": "c" }"#)],
+        );
+        let new = lock(
+            "a",
+            1,
+            &[("systems", r#"{ "type": "path", "lastModified": 1 }"#)],
+        );
+
+        let changes = old.diff(&new);
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0].input, "flake-utils");
+        assert_eq!(changes[0].old.as_ref().unwrap().to_string(), "c");
+        assert!(changes[0].new.is_none());
+        assert_eq!(changes[1].input, "systems");
+        assert!(changes[1].old.is_none());
+        assert_eq!(changes[1].new.as_ref().unwrap().to_string(), "1970-01-01");
+        assert_eq!(changes[1].compare_url, None);
+    }
+}
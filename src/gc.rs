@@ -0,0 +1,327 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::fmt::Display;
+use std::str::FromStr;
+
+use camino::Utf8PathBuf;
+use miette::miette;
+use miette::Context;
+use miette::IntoDiagnostic;
+
+use crate::cli::GcArgs;
+use crate::config::Config;
+use crate::nix::Nix;
+
+/// An age in days, written like `30d`, as `nix profile wipe-history --older-than` expects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct Age {
+    days: u32,
+}
+
+impl FromStr for Age {
+    type Err = miette::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.strip_suffix('d')
+            .and_then(|days| days.parse().ok())
+            .map(|days| Self { days })
+            .ok_or_else(|| miette!("Expected a number of days, like `30d`, not {s:?}"))
+    }
+}
+
+impl schemars::JsonSchema for Age {
+    fn schema_name() -> String {
+        "Age".to_owned()
+    }
+
+    fn json_schema(_gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        schemars::schema::SchemaObject {
+            instance_type: Some(schemars::schema::InstanceType::String.into()),
+            string: Some(Box::new(schemars::schema::StringValidation {
+                pattern: Some("^[0-9]+d$".to_owned()),
+                ..Default::default()
+            })),
+            metadata: Some(Box::new(schemars::schema::Metadata {
+                description: Some("An age in days, like `30d`.".to_owned()),
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+        .into()
+    }
+}
+
+impl TryFrom<String> for Age {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse().map_err(|err: miette::Report| err.to_string())
+    }
+}
+
+impl Display for Age {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}d", self.days)
+    }
+}
+
+/// How many generations to keep when collecting garbage after a switch without
+/// `keep-generations` or `older-than`.
+pub const DEFAULT_KEEP_GENERATIONS: u32 = 5;
+
+/// Options for [`collect_garbage`].
+#[derive(Debug, Clone, Default)]
+pub struct GcOptions {
+    /// Keep at most this many generations of the profile.
+    pub keep_generations: Option<u32>,
+    /// Delete generations older than this.
+    pub older_than: Option<Age>,
+    /// Run `nix store gc` afterwards.
+    pub store: bool,
+}
+
+/// A summary of what [`collect_garbage`] deleted.
+#[derive(serde::Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct GcReport {
+    pub deleted_generations: Vec<u64>,
+    pub deleted_store_paths: Option<u64>,
+    pub freed_bytes: Option<u64>,
+}
+
+/// Delete old profile generations and, optionally, unreachable store paths.
+pub fn gc(config: &Config, args: &GcArgs) -> miette::Result<()> {
+    let nix = config.nix()?;
+    let report = collect_garbage(&nix, &config.gc_options(Some(args)))?;
+
+    if config.json() {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&report).into_diagnostic()?
+        );
+    }
+
+    Ok(())
+}
+
+/// Delete old profile generations and, optionally, unreachable store paths.
+///
+/// With both `keep_generations` and `older_than`, generations matching either are deleted.
+/// With neither, all generations but the current one are deleted.
+#[tracing::instrument(level = "debug", skip(nix))]
+pub fn collect_garbage(nix: &Nix, options: &GcOptions) -> miette::Result<GcReport> {
+    let mut report = GcReport::default();
+    let before = nix.profile_generations()?;
+
+    if let Some(count) = options.keep_generations {
+        if count == 0 {
+            return Err(miette!(
+                "Can't keep 0 generations; the current generation is always kept"
+            ));
+        }
+        nix.keep_generations(count)?;
+    }
+    if options.older_than.is_some() || options.keep_generations.is_none() {
+        nix.wipe_history(options.older_than.map(|age| age.to_string()).as_deref())?;
+    }
+
+    let after = nix.profile_generations()?;
+    report.deleted_generations = before
+        .into_iter()
+        .filter(|generation| !after.contains(generation))
+        .collect();
+    match report.deleted_generations.len() {
+        0 => tracing::info!("No profile generations to delete"),
+        1 => tracing::info!("Deleted 1 old profile generation"),
+        count => tracing::info!("Deleted {count} old profile generations"),
+    }
+
+    if options.store {
+        tracing::info!("Collecting garbage in the Nix store");
+        let result = nix.store_gc()?;
+        report.deleted_store_paths = result.deleted_paths;
+        report.freed_bytes = result.freed_bytes;
+        match (result.deleted_paths, result.freed_bytes) {
+            (Some(paths), Some(bytes)) => {
+                tracing::info!(
+                    "Deleted {paths} store paths, freeing {}",
+                    format_bytes(bytes)
+                );
+            }
+            (_, Some(bytes)) => tracing::info!("Freed {}", format_bytes(bytes)),
+            _ => tracing::info!("Collected garbage in the Nix store"),
+        }
+    }
+
+    Ok(report)
+}
+
+/// Format a byte count like `nix store gc` does, like `567.89 MiB`.
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+    let mut amount = bytes as f64;
+    let mut unit = "bytes";
+    for next in UNITS {
+        if amount < 1024.0 {
+            break;
+        }
+        amount /= 1024.0;
+        unit = next;
+    }
+    if unit == "bytes" {
+        format!("{bytes} bytes")
+    } else {
+        format!("{amount:.2} {unit}")
+    }
+}
+
+/// Indirect garbage collector roots for built packages, so that they aren't deleted between
+/// building and installing, and rolling back never requires rebuilding.
+#[derive(Debug, Clone)]
+pub struct GcRoots {
+    directory: Utf8PathBuf,
+    /// How many builds to keep roots for.
+    keep: u32,
+}
+
+impl GcRoots {
+    pub fn new(directory: Utf8PathBuf, keep: u32) -> Self {
+        Self { directory, keep }
+    }
+
+    /// A new `--out-link` path for a build.
+    ///
+    /// For packages with several outputs, Nix adds links with the output names appended.
+    pub fn out_link(&self, name: &str) -> miette::Result<Utf8PathBuf> {
+        std::fs::create_dir_all(&self.directory)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to create directory {}", self.directory))?;
+        let timestamp = jiff::Timestamp::now().strftime(ROOT_TIMESTAMP_FORMAT);
+        Ok(self.directory.join(format!("{timestamp}-{name}")))
+    }
+
+    /// Remove roots for all but the newest `keep` builds, and roots for builds identical to a
+    /// newer one.
+    ///
+    /// This is best-effort: failures are logged as warnings, and links which can't be read are
+    /// left alone.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub fn prune(&self) {
+        // Map from timestamps (which sort oldest first) to links and their targets.
+        let mut builds: BTreeMap<String, Vec<(Utf8PathBuf, Utf8PathBuf)>> = BTreeMap::new();
+        let entries = match self.directory.read_dir_utf8() {
+            Ok(entries) => entries,
+            Err(err) => {
+                tracing::warn!(
+                    "Failed to list garbage collector roots in {}: {err}",
+                    self.directory
+                );
+                return;
+            }
+        };
+        for entry in entries {
+            let path = match entry {
+                Ok(entry) => entry.into_path(),
+                Err(err) => {
+                    tracing::warn!(
+                        "Failed to list garbage collector roots in {}: {err}",
+                        self.directory
+                    );
+                    continue;
+                }
+            };
+            let Some(timestamp) = path.file_name().and_then(build_timestamp) else {
+                continue;
+            };
+            let timestamp = timestamp.to_owned();
+            let target = match path.read_link_utf8() {
+                Ok(target) => target,
+                Err(err) => {
+                    tracing::warn!("Skipping unreadable garbage collector root {path}: {err}");
+                    continue;
+                }
+            };
+            builds.entry(timestamp).or_default().push((path, target));
+        }
+
+        let mut kept = 0;
+        let mut seen = BTreeSet::new();
+        for links in builds.values().rev() {
+            let targets = links
+                .iter()
+                .map(|(_, target)| target.clone())
+                .collect::<BTreeSet<_>>();
+            if kept < self.keep && !seen.contains(&targets) {
+                kept += 1;
+                seen.insert(targets);
+                continue;
+            }
+
+            for (link, _) in links {
+                tracing::debug!(%link, "Removing garbage collector root");
+                if let Err(err) = std::fs::remove_file(link) {
+                    tracing::warn!("Failed to remove garbage collector root {link}: {err}");
+                }
+            }
+        }
+    }
+}
+
+/// The format of the timestamps at the start of garbage collector root names.
+const ROOT_TIMESTAMP_FORMAT: &str = "%Y%m%dT%H%M%S%.3fZ";
+
+/// Get the build timestamp from the name of a garbage collector root, like
+/// `20240101T120000.000Z-home-mangler-packages-man`.
+///
+/// Returns `None` for files not created by [`GcRoots::out_link`].
+fn build_timestamp(name: &str) -> Option<&str> {
+    let (timestamp, _) = name.split_once('-')?;
+    jiff::civil::DateTime::strptime(ROOT_TIMESTAMP_FORMAT, timestamp).ok()?;
+    Some(timestamp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_age() {
+        let age: Age = "30d".parse().unwrap();
+        assert_eq!(age, Age { days: 30 });
+        assert_eq!(age.to_string(), "30d");
+        assert!("30".parse::<Age>().is_err());
+        assert!("1w".parse::<Age>().is_err());
+        assert!("-1d".parse::<Age>().is_err());
+    }
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(512), "512 bytes");
+        assert_eq!(format_bytes(595_475_824), "567.89 MiB");
+        assert_eq!(format_bytes(3 << 30), "3.00 GiB");
+    }
+
+    #[test]
+    fn test_build_timestamp() {
+        assert_eq!(
+            build_timestamp("20240101T120000.000Z-home-mangler-packages"),
+            Some("20240101T120000.000Z")
+        );
+        assert_eq!(
+            build_timestamp("20240101T120000.000Z-home-mangler-packages-man"),
+            Some("20240101T120000.000Z")
+        );
+        assert_eq!(build_timestamp("home-mangler-packages"), None);
+        assert_eq!(build_timestamp("result"), None);
+    }
+
+    #[test]
+    fn test_build_timestamp_round_trip() {
+        let timestamp = jiff::Timestamp::now()
+            .strftime(ROOT_TIMESTAMP_FORMAT)
+            .to_string();
+        let name = format!("{timestamp}-home-mangler-packages");
+        assert_eq!(build_timestamp(&name), Some(timestamp.as_str()));
+    }
+}
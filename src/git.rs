@@ -0,0 +1,113 @@
+use std::process::Command;
+
+use camino::Utf8Path;
+use camino::Utf8PathBuf;
+use command_error::CommandExt;
+use miette::Context;
+use miette::IntoDiagnostic;
+
+use crate::timings::CommandSpanExt;
+
+/// A Git working tree.
+#[derive(Debug, Clone)]
+pub struct GitCheckout {
+    /// A directory within the working tree. Relative paths are resolved from here.
+    path: Utf8PathBuf,
+}
+
+impl GitCheckout {
+    /// Find the Git working tree containing `path`, if any.
+    pub fn find(path: &Utf8Path) -> miette::Result<Option<Self>> {
+        let output = Command::new("git")
+            .current_dir(path)
+            .args(["rev-parse", "--is-inside-work-tree"])
+            .in_span(|command| command.output())
+            .into_diagnostic()
+            .wrap_err("Failed to run `git`")?;
+
+        if output.status.success() && output.stdout.trim_ascii() == b"true" {
+            Ok(Some(Self {
+                path: path.to_owned(),
+            }))
+        } else {
+            tracing::debug!(%path, "Not a Git working tree");
+            Ok(None)
+        }
+    }
+
+    fn command(&self) -> Command {
+        let mut command = Command::new("git");
+        command.current_dir(&self.path);
+        command
+    }
+
+    /// Does the given file have uncommitted changes (including being untracked)?
+    pub fn is_dirty(&self, file: &Utf8Path) -> miette::Result<bool> {
+        let stdout = self
+            .command()
+            .args(["status", "--porcelain", "--"])
+            .arg(file)
+            .in_span(|command| command.output_checked_utf8())
+            .into_diagnostic()?
+            .stdout;
+
+        Ok(!stdout.trim().is_empty())
+    }
+
+    /// Does the working tree have uncommitted changes?
+    ///
+    /// Untracked files are only counted if `include_untracked` is set; Git flakes ignore them,
+    /// but `path:` flakes don't.
+    pub fn is_worktree_dirty(&self, include_untracked: bool) -> miette::Result<bool> {
+        let untracked = if include_untracked {
+            "--untracked-files=normal"
+        } else {
+            "--untracked-files=no"
+        };
+        let stdout = self
+            .command()
+            .args(["status", "--porcelain", untracked])
+            .in_span(|command| command.output_checked_utf8())
+            .into_diagnostic()?
+            .stdout;
+
+        Ok(!stdout.trim().is_empty())
+    }
+
+    /// The commit checked out, if there is one.
+    pub fn head_revision(&self) -> miette::Result<Option<String>> {
+        let output = self
+            .command()
+            .args(["rev-parse", "--verify", "--quiet", "HEAD"])
+            .in_span(|command| command.output())
+            .into_diagnostic()
+            .wrap_err("Failed to run `git`")?;
+
+        if output.status.success() {
+            Ok(Some(
+                String::from_utf8_lossy(&output.stdout).trim().to_owned(),
+            ))
+        } else {
+            // There are no commits yet.
+            Ok(None)
+        }
+    }
+
+    /// Commit the given file, and only the given file.
+    #[tracing::instrument(level = "debug", skip(self, message))]
+    pub fn commit_file(&self, file: &Utf8Path, message: &str) -> miette::Result<()> {
+        self.command()
+            .args(["add", "--"])
+            .arg(file)
+            .in_span(|command| command.status_checked())
+            .into_diagnostic()?;
+
+        self.command()
+            .args(["commit", "--quiet", "--message", message, "--"])
+            .arg(file)
+            .in_span(|command| command.status_checked())
+            .into_diagnostic()?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,228 @@
+use std::collections::BTreeSet;
+use std::fs::OpenOptions;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+
+use camino::Utf8Path;
+use camino::Utf8PathBuf;
+use miette::miette;
+use miette::Context;
+use miette::IntoDiagnostic;
+use owo_colors::OwoColorize;
+use owo_colors::Stream;
+
+use crate::cli::LogArgs;
+use crate::flake_lock::InputChange;
+use crate::nix::FlakeMetadata;
+use crate::packages::PackagesReport;
+
+/// Where a switch's configuration came from.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct FlakeProvenance {
+    pub original_url: String,
+    pub resolved_url: String,
+    /// The Git revision of the flake, if it was a clean Git tree.
+    pub revision: Option<String>,
+    /// The Git revision of the flake, if it was a dirty Git tree.
+    pub dirty_revision: Option<String>,
+    /// Unix timestamp.
+    pub last_modified: Option<i64>,
+    pub dirty: bool,
+}
+
+impl From<&FlakeMetadata> for FlakeProvenance {
+    fn from(metadata: &FlakeMetadata) -> Self {
+        Self {
+            original_url: metadata.original_url.clone(),
+            resolved_url: metadata.resolved_url.clone(),
+            revision: metadata.revision.clone(),
+            dirty_revision: metadata.dirty_revision.clone(),
+            last_modified: metadata.last_modified,
+            dirty: metadata.dirty_revision.is_some(),
+        }
+    }
+}
+
+/// A record of a single switch, stored in the history log.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryEntry {
+    pub timestamp: jiff::Timestamp,
+    pub hostname: String,
+    pub flake: FlakeProvenance,
+    /// Inputs changed by `nix flake update`, if the flake was updated.
+    pub input_changes: Option<Vec<InputChange>>,
+    /// The store paths built for the configuration.
+    pub out_paths: BTreeSet<Utf8PathBuf>,
+    /// Store paths removed from the profile.
+    pub removed_paths: BTreeSet<Utf8PathBuf>,
+    /// Store paths added to the profile.
+    pub added_paths: BTreeSet<Utf8PathBuf>,
+    /// Did the switch change the profile?
+    ///
+    /// Older history logs only recorded switches which changed the profile.
+    #[serde(default = "default_changed")]
+    pub changed: bool,
+}
+
+fn default_changed() -> bool {
+    true
+}
+
+impl HistoryEntry {
+    pub fn new(hostname: &str, report: &PackagesReport) -> miette::Result<Self> {
+        Ok(Self {
+            timestamp: jiff::Timestamp::now(),
+            hostname: hostname.to_owned(),
+            flake: report
+                .flake
+                .clone()
+                .ok_or_else(|| miette!("Switch has no flake metadata to record"))?,
+            input_changes: report.input_changes.clone(),
+            out_paths: report.out_paths.clone(),
+            removed_paths: report.removed_paths.clone(),
+            added_paths: report.added_paths.clone(),
+            changed: report.changed(),
+        })
+    }
+}
+
+/// Append an entry to the history log at `path`.
+#[tracing::instrument(level = "debug", skip(entry))]
+pub fn append(path: &Utf8Path, entry: &HistoryEntry) -> miette::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to create directory {parent}"))?;
+    }
+
+    let mut line = serde_json::to_string(entry).into_diagnostic()?;
+    line.push('\n');
+
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(line.as_bytes()))
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to write history to {path}"))?;
+
+    tracing::debug!(%path, "Recorded switch in history");
+    Ok(())
+}
+
+/// Read all the entries in the history log at `path`, oldest first.
+pub fn read(path: &Utf8Path) -> miette::Result<Vec<HistoryEntry>> {
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(Vec::new());
+        }
+        Err(err) => {
+            return Err(err)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to open {path}"));
+        }
+    };
+
+    let mut ret = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to read {path}"))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = serde_json::from_str(&line)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to parse {path} line {}", i + 1))?;
+        ret.push(entry);
+    }
+
+    Ok(ret)
+}
+
+/// Print the history log at `path`, most recent first.
+pub fn show_log(path: &Utf8Path, args: &LogArgs) -> miette::Result<()> {
+    let entries = read(path)?;
+    let entries = entries.iter().rev().take(args.limit.unwrap_or(usize::MAX));
+
+    if args.json {
+        for entry in entries {
+            println!("{}", serde_json::to_string(entry).into_diagnostic()?);
+        }
+        return Ok(());
+    }
+
+    for entry in entries {
+        println!("{}", format_entry(entry));
+    }
+
+    Ok(())
+}
+
+fn format_entry(entry: &HistoryEntry) -> String {
+    let mut ret = format!(
+        "{} {} {}",
+        entry
+            .timestamp
+            .to_zoned(jiff::tz::TimeZone::system())
+            .strftime("%Y-%m-%d %H:%M:%S %Z")
+            .if_supports_color(Stream::Stdout, |text| text.bold()),
+        entry.hostname,
+        entry.flake.resolved_url,
+    );
+
+    if let Some(revision) = entry
+        .flake
+        .revision
+        .as_deref()
+        .or(entry.flake.dirty_revision.as_deref())
+    {
+        ret.push_str(&format!(" @ {revision}"));
+    }
+    if !entry.changed {
+        ret.push_str(
+            &" (no changes)"
+                .if_supports_color(Stream::Stdout, |text| text.dimmed())
+                .to_string(),
+        );
+    }
+    ret.push('\n');
+
+    for change in entry.input_changes.iter().flatten() {
+        let old = change
+            .old
+            .as_ref()
+            .map(|rev| rev.to_string())
+            .unwrap_or_else(|| "(added)".to_owned());
+        let new = change
+            .new
+            .as_ref()
+            .map(|rev| rev.to_string())
+            .unwrap_or_else(|| "(removed)".to_owned());
+        ret.push_str(&format!("  ~ {}: {old} → {new}\n", change.input));
+    }
+
+    for path in &entry.removed_paths {
+        ret.push_str(
+            &format!("  - {path}")
+                .if_supports_color(Stream::Stdout, |text| text.red())
+                .to_string(),
+        );
+        ret.push('\n');
+    }
+
+    for path in &entry.added_paths {
+        ret.push_str(
+            &format!("  + {path}")
+                .if_supports_color(Stream::Stdout, |text| text.green())
+                .to_string(),
+        );
+        ret.push('\n');
+    }
+
+    ret
+}
@@ -1,35 +1,71 @@
-use clap::Parser;
-
+mod adopt;
 mod cli;
 mod config;
+mod confirm;
+mod conflicts;
 mod diff_trees;
 mod directories;
 mod flake;
+mod flake_lock;
 mod format_bulleted_list;
+mod gc;
+mod git;
+mod history;
+mod migrate_profile;
 mod nix;
 mod packages;
+mod status;
+mod switch;
+mod symlinks;
+mod timings;
 mod tracing;
+mod update;
 
+use cli::Command;
 use config::Config;
+use timings::Timings;
 
 pub use directories::ProjectPaths;
 pub use format_bulleted_list::format_bulleted_list;
 
 fn main() -> miette::Result<()> {
-    let opts = cli::Args::parse();
-    let filter_reload = tracing::install_tracing(
+    let opts = cli::Args::parse_with_sources();
+    let timings_options = opts.timings_options();
+    let timings = timings_options
+        .enabled()
+        .then(|| Timings::new(timings_options));
+    let tracing_handles = tracing::install_tracing(
         opts.log_filter()
             .as_deref()
             .unwrap_or(tracing::DEFAULT_FILTER),
+        opts.log_format,
+        timings.as_ref(),
     )?;
+    if let Some(Command::Config { command }) = &opts.command {
+        if let Some(result) = config::run_standalone_command(&opts, command) {
+            return result;
+        }
+    }
+
     let config = Config::from_args(opts)?;
-    tracing::update_log_filters(&filter_reload, &config.log_filter())?;
+    tracing_handles.update_log_filters(&config.log_filter())?;
+    if let Some(directory) = config.log_file_dir()? {
+        tracing_handles.enable_log_file(&directory)?;
+    }
+
+    let result = match config.command() {
+        Some(Command::Log(args)) => history::show_log(&config.history_path()?, args),
+        Some(Command::Config { command }) => config::run_command(&config, command),
+        Some(Command::MigrateProfile(args)) => migrate_profile::migrate_profile(&config, args),
+        Some(Command::Status(args)) => status::status(&config, args),
+        Some(Command::Adopt(args)) => adopt::adopt(&config, args),
+        Some(Command::Gc(args)) => gc::gc(&config, args),
+        None => switch::switch(&config),
+    };
 
-    let nix = config.nix()?;
-    let flake = config.flake()?;
-    let hostname = config.hostname()?;
-    ::tracing::debug!(%flake, %hostname, "Resolved configuration");
-    packages::ensure_packages(&nix, &flake, &hostname, config.update())?;
+    if let Some(timings) = timings {
+        timings.finish()?;
+    }
 
-    Ok(())
+    result
 }
@@ -0,0 +1,105 @@
+use std::collections::BTreeSet;
+
+use miette::miette;
+use miette::Context;
+use miette::IntoDiagnostic;
+
+use crate::cli::MigrateProfileArgs;
+use crate::config::Config;
+use crate::format_bulleted_list;
+use crate::nix::is_legacy_profile;
+use crate::packages::flake_attr;
+use crate::switch::switch_with_nix;
+
+/// Convert a `nix-env` profile to a `nix profile` profile and install the home-mangler
+/// packages into it.
+pub fn migrate_profile(config: &Config, args: &MigrateProfileArgs) -> miette::Result<()> {
+    let nix = config.nix()?;
+    let profile = nix.profile_path().ok_or_else(|| {
+        miette!(
+            help = "Use `--profile` to select a profile",
+            "Could not find your Nix profile"
+        )
+    })?;
+
+    if !is_legacy_profile(&profile) {
+        tracing::info!("{profile} is already a `nix profile` profile; nothing to migrate");
+        return Ok(());
+    }
+
+    let legacy = nix.legacy_packages()?;
+
+    let flake = config.flake()?;
+    let installable = format!("{flake}#{}", flake_attr(config.hostname()));
+    if args.dry_run {
+        // Finding the packages' runtime closure requires building them.
+        tracing::info!("Building packages to compare with `nix-env` packages");
+    } else {
+        tracing::info!("Building packages for install");
+    }
+    let out_paths = nix.build(&installable, None)?;
+    let closure = nix.closure(&out_paths)?;
+
+    // Packages whose outputs are part of the new package set are kept.
+    let (kept, dropped): (Vec<_>, Vec<_>) = legacy
+        .packages
+        .iter()
+        .partition(|package| package.store_paths().any(|path| closure.contains(path)));
+    let kept = kept
+        .iter()
+        .map(|package| &package.name)
+        .collect::<BTreeSet<_>>();
+    let dropped = dropped
+        .iter()
+        .map(|package| &package.name)
+        .collect::<BTreeSet<_>>();
+
+    if !kept.is_empty() {
+        tracing::info!(
+            "These `nix-env` packages are included in the home-mangler packages:\n{}",
+            format_bulleted_list(&kept)
+        );
+    }
+    if !dropped.is_empty() {
+        tracing::info!(
+            "These `nix-env` packages will be dropped:\n{}",
+            format_bulleted_list(&dropped)
+        );
+    }
+
+    if args.dry_run {
+        tracing::info!("Dry run; built packages but not migrating {profile}");
+        return Ok(());
+    }
+
+    let state_dir = config.state_dir()?;
+    std::fs::create_dir_all(&state_dir)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to create directory {state_dir}"))?;
+    let record_path = state_dir.join(format!(
+        "nix-env-packages-{}.json",
+        jiff::Timestamp::now().strftime("%Y%m%dT%H%M%SZ")
+    ));
+    std::fs::write(
+        &record_path,
+        serde_json::to_string_pretty(&legacy.json).into_diagnostic()?,
+    )
+    .into_diagnostic()
+    .wrap_err_with(|| format!("Failed to write {record_path}"))?;
+    tracing::info!("Recorded `nix-env` packages in {record_path}");
+
+    // Install the packages into a new profile, then switch to it in a new generation. The old
+    // generations are left alone, so this can be undone with `nix-env --rollback`.
+    tracing::info!("Converting {profile} to a `nix profile` profile");
+    let staged = nix.stage_new_profile()?;
+    let message = format!("Failed to migrate {profile}; the `nix-env` profile is unchanged");
+    switch_with_nix(config, staged.nix()).wrap_err_with(|| message.clone())?;
+    if !staged.commit(&nix)? {
+        return Err(miette!(
+            help = "Check that the `packages` step is enabled",
+            "{message}: no packages were installed"
+        ));
+    }
+
+    Ok(())
+}
@@ -1,28 +1,81 @@
+use std::collections::BTreeMap;
 use std::collections::BTreeSet;
-use std::process::Stdio;
 
+use camino::Utf8Path;
 use camino::Utf8PathBuf;
-use command_error::CommandExt;
+use miette::Context;
 use miette::IntoDiagnostic;
 
 use super::Nix;
 
 impl Nix {
     /// Build an installable and return the out paths.
-    pub fn build(&self, installable: &str) -> miette::Result<BTreeSet<Utf8PathBuf>> {
-        let stdout = self
-            .command(&["build"])
-            .args([
-                "--print-build-logs",
-                "--no-link",
-                "--print-out-paths",
-                installable,
-            ])
-            .stderr(Stdio::inherit())
-            .output_checked_utf8()
-            .into_diagnostic()?
-            .stdout;
+    ///
+    /// If `out_link` is given, the outputs are registered as garbage collector roots there.
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub fn build(
+        &self,
+        installable: &str,
+        out_link: Option<&Utf8Path>,
+    ) -> miette::Result<BTreeSet<Utf8PathBuf>> {
+        let print_out_paths = self.capabilities().build_print_out_paths;
+        let mut command = self.command(&["build"]);
+        command.arg("--print-build-logs");
+        command.arg(if print_out_paths {
+            "--print-out-paths"
+        } else {
+            "--json"
+        });
+        match out_link {
+            Some(out_link) => command.args(["--out-link", out_link.as_str()]),
+            None => command.arg("--no-link"),
+        };
+        let stdout = self.run(command.arg(installable))?;
+
+        if print_out_paths {
+            Ok(stdout.lines().map(Utf8PathBuf::from).collect())
+        } else {
+            parse_build_json(&stdout)
+        }
+    }
+}
+
+/// A result from `nix build --json`.
+#[derive(serde::Deserialize)]
+struct BuildResult {
+    /// Map from output names to store paths.
+    outputs: BTreeMap<String, Utf8PathBuf>,
+}
+
+/// Parse the out paths from `nix build --json` output, like
+/// `[{"drvPath":"/nix/store/...-hello-2.12.drv","outputs":{"out":"/nix/store/...-hello-2.12"}}]`.
+fn parse_build_json(stdout: &str) -> miette::Result<BTreeSet<Utf8PathBuf>> {
+    let results: Vec<BuildResult> = serde_json::from_str(stdout)
+        .into_diagnostic()
+        .wrap_err("Failed to parse `nix build --json` output")?;
+    Ok(results
+        .into_iter()
+        .flat_map(|result| result.outputs.into_values())
+        .collect())
+}
 
-        Ok(stdout.lines().map(Utf8PathBuf::from).collect())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_build_json() {
+        let out_paths = parse_build_json(
+            r#"[{"drvPath":"/nix/store/bbb-home-mangler-packages.drv","outputs":{"man":"/nix/store/ccc-home-mangler-packages-man","out":"/nix/store/ddd-home-mangler-packages"}}]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            out_paths,
+            BTreeSet::from([
+                Utf8PathBuf::from("/nix/store/ccc-home-mangler-packages-man"),
+                Utf8PathBuf::from("/nix/store/ddd-home-mangler-packages"),
+            ])
+        );
+        assert!(parse_build_json("/nix/store/ddd-home-mangler-packages").is_err());
     }
 }
@@ -0,0 +1,210 @@
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStrExt;
+use std::process::Command;
+
+use camino::Utf8PathBuf;
+use command_error::CommandExt;
+use miette::miette;
+use miette::IntoDiagnostic;
+
+use crate::timings::CommandSpanExt;
+
+/// The environment to run `nix` in.
+///
+/// When home-mangler runs from a systemd timer or `cron`, the environment is usually missing
+/// the variables set by the Nix installer, like `NIX_PATH`, `NIX_SSL_CERT_FILE`, and the
+/// `PATH` entries for `nix` itself.
+#[derive(schemars::JsonSchema, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum NixEnvironment {
+    /// `"inherit"` or `"login-shell"`.
+    Mode(NixEnvironmentMode),
+    /// Source a shell script and use the resulting environment.
+    Source {
+        /// A script to source with `sh`, like
+        /// `/nix/var/nix/profiles/default/etc/profile.d/nix-daemon.sh`.
+        #[schemars(with = "String")]
+        source: Utf8PathBuf,
+    },
+}
+
+impl Default for NixEnvironment {
+    fn default() -> Self {
+        Self::Mode(NixEnvironmentMode::Inherit)
+    }
+}
+
+// Implemented by hand because an untagged enum gives unhelpful error messages.
+impl<'de> serde::Deserialize<'de> for NixEnvironment {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct Visitor;
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = NixEnvironment;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str(
+                    r#"`"inherit"`, `"login-shell"`, or `{ source = "/path/to/script" }`"#,
+                )
+            }
+
+            fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                match value {
+                    "inherit" => Ok(NixEnvironment::Mode(NixEnvironmentMode::Inherit)),
+                    "login-shell" => Ok(NixEnvironment::Mode(NixEnvironmentMode::LoginShell)),
+                    _ => Err(E::invalid_value(serde::de::Unexpected::Str(value), &self)),
+                }
+            }
+
+            fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+            where
+                A: serde::de::MapAccess<'de>,
+            {
+                let mut source = None;
+                while let Some(key) = map.next_key::<String>()? {
+                    match key.as_str() {
+                        "source" => source = Some(map.next_value::<Utf8PathBuf>()?),
+                        _ => return Err(serde::de::Error::unknown_field(&key, &["source"])),
+                    }
+                }
+                match source {
+                    Some(source) => Ok(NixEnvironment::Source { source }),
+                    None => Err(serde::de::Error::missing_field("source")),
+                }
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+#[derive(serde::Deserialize, schemars::JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum NixEnvironmentMode {
+    /// Use home-mangler's own environment.
+    Inherit,
+    /// Use the environment of a login shell (`$SHELL -l`).
+    LoginShell,
+}
+
+/// Marks the start of the environment in the shell's output, in case the shell's startup files
+/// print anything.
+const ENV_MARKER: &str = "__HOME_MANGLER_ENV__";
+
+/// A shell command to print the environment after [`ENV_MARKER`].
+const PRINT_ENV: &str = "printf '\\0__HOME_MANGLER_ENV__\\0' && env -0";
+
+/// Environment variables captured for `nix` commands.
+pub type Environment = BTreeMap<OsString, OsString>;
+
+impl NixEnvironment {
+    /// Capture the environment, or `None` to inherit home-mangler's environment.
+    #[tracing::instrument(level = "debug")]
+    pub fn capture(&self) -> miette::Result<Option<Environment>> {
+        let mut command = match self {
+            NixEnvironment::Mode(NixEnvironmentMode::Inherit) => {
+                return Ok(None);
+            }
+            NixEnvironment::Mode(NixEnvironmentMode::LoginShell) => {
+                let shell = std::env::var_os("SHELL").unwrap_or_else(|| "/bin/sh".into());
+                let mut command = Command::new(shell);
+                command.args(["-l", "-c", PRINT_ENV]);
+                command
+            }
+            NixEnvironment::Source { source } => {
+                if !source.exists() {
+                    return Err(miette!(
+                        "Cannot source Nix environment from {source}: file does not exist"
+                    ));
+                }
+                let mut command = Command::new("/bin/sh");
+                command.args([
+                    "-c",
+                    &format!(". \"$1\" >&2 && {PRINT_ENV}"),
+                    "sh",
+                    source.as_str(),
+                ]);
+                command
+            }
+        };
+
+        let stdout = command
+            .in_span(|command| command.output_checked())
+            .into_diagnostic()?
+            .stdout;
+
+        let environment = parse_env(&stdout);
+        tracing::debug!(variables = environment.len(), "Captured Nix environment");
+        Ok(Some(environment))
+    }
+}
+
+/// Parse the output of [`PRINT_ENV`].
+fn parse_env(output: &[u8]) -> Environment {
+    output
+        .split(|byte| *byte == 0)
+        .skip_while(|entry| *entry != ENV_MARKER.as_bytes())
+        .skip(1)
+        .filter_map(|entry| {
+            let split = entry.iter().position(|byte| *byte == b'=')?;
+            let (name, value) = entry.split_at(split);
+            if name.is_empty() {
+                return None;
+            }
+            Some((
+                OsStr::from_bytes(name).to_owned(),
+                OsStr::from_bytes(&value[1..]).to_owned(),
+            ))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(entries: &[(&str, &str)]) -> Environment {
+        entries
+            .iter()
+            .map(|(name, value)| (OsString::from(name), OsString::from(value)))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_env() {
+        // Login shells may print a greeting before the marker.
+        let output = b"Welcome!\n\0__HOME_MANGLER_ENV__\0\
+            PATH=/nix/var/nix/profiles/default/bin:/usr/bin\0\
+            NIX_SSL_CERT_FILE=/etc/ssl/certs/ca-certificates.crt\0\
+            MULTILINE=a\nb=c\0\
+            EMPTY=\0";
+        assert_eq!(
+            parse_env(output),
+            env(&[
+                ("PATH", "/nix/var/nix/profiles/default/bin:/usr/bin"),
+                ("NIX_SSL_CERT_FILE", "/etc/ssl/certs/ca-certificates.crt"),
+                ("MULTILINE", "a\nb=c"),
+                ("EMPTY", ""),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_env_skips_invalid_entries() {
+        let output = b"\0__HOME_MANGLER_ENV__\0=value\0NOT_A_VARIABLE\0HOME=/home/me\0";
+        assert_eq!(parse_env(output), env(&[("HOME", "/home/me")]));
+    }
+
+    #[test]
+    fn test_parse_env_without_marker() {
+        assert_eq!(parse_env(b"PATH=/usr/bin\0"), Environment::new());
+    }
+}
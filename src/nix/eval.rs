@@ -0,0 +1,20 @@
+use miette::Context;
+use miette::IntoDiagnostic;
+
+use super::Nix;
+
+impl Nix {
+    /// Evaluate an installable and deserialize the JSON result.
+    ///
+    /// Derivations are serialized as their out paths.
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub fn eval_json<T: serde::de::DeserializeOwned>(
+        &self,
+        installable: &str,
+    ) -> miette::Result<T> {
+        let stdout = self.run(self.command(&["eval"]).args(["--json", installable]))?;
+        serde_json::from_str(&stdout)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to parse `nix eval --json {installable}` output"))
+    }
+}
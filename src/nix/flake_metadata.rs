@@ -1,10 +1,11 @@
 use std::collections::HashMap;
 
 use camino::Utf8PathBuf;
-use command_error::CommandExt;
+use miette::miette;
 use miette::IntoDiagnostic;
 
 use crate::flake::Flake;
+use crate::flake_lock::FlakeLock;
 
 use super::Nix;
 
@@ -27,19 +28,56 @@ pub struct FlakeMetadata {
     pub original_url: String,
     pub resolved: HashMap<String, String>,
     pub resolved_url: String,
-    // Other fields: `locked`, `locks`, `lastModified`, `dirtyRevision`, `description`,
-    // `revCount`, `revision`.
+    /// The contents of the `flake.lock` file, if any.
+    pub locks: Option<FlakeLock>,
+    /// The Git revision of the flake source, if it's a clean Git tree.
+    pub revision: Option<String>,
+    /// The Git revision of the flake source with a `-dirty` suffix, if it's a dirty Git tree.
+    pub dirty_revision: Option<String>,
+    /// Unix timestamp.
+    pub last_modified: Option<i64>,
+    // Other fields: `locked`, `description`, `revCount`.
+}
+
+impl FlakeMetadata {
+    /// Check if the flake is a dirty Git tree.
+    ///
+    /// If `require_clean` is set, dirty trees are an error. Otherwise, we print a warning.
+    pub fn check_clean(&self, require_clean: bool) -> miette::Result<()> {
+        match &self.dirty_revision {
+            Some(dirty_revision) => report_dirty(
+                &format!("{} ({dirty_revision})", self.resolved_url),
+                require_clean,
+            ),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Report that `flake` is a dirty Git tree: an error if `require_clean` is set, and a warning
+/// otherwise.
+pub fn report_dirty(flake: &str, require_clean: bool) -> miette::Result<()> {
+    if require_clean {
+        Err(miette!(
+            help = "Commit or stash your changes, or disable `require-clean`",
+            "Refusing to switch from a dirty Git tree: {flake}"
+        ))
+    } else {
+        tracing::warn!(
+            "{flake} has uncommitted changes; the configuration will not be reproducible from a Git revision.\nSet `require-clean = true` to refuse to switch from a dirty tree."
+        );
+        Ok(())
+    }
 }
 
 impl Nix {
+    #[tracing::instrument(level = "debug", skip_all, fields(%flake))]
     pub fn flake_metadata(&self, flake: &Flake) -> miette::Result<FlakeMetadata> {
         tracing::info!("Resolving flake metadata");
-        let json_output = self
-            .command(&["flake", "metadata"])
-            .args(["--json", &flake.to_string()])
-            .output_checked_utf8()
-            .into_diagnostic()?
-            .stdout;
+        let json_output = self.run(
+            self.command(&["flake", "metadata"])
+                .args(["--json", &flake.to_string()]),
+        )?;
 
         serde_json::from_str(&json_output).into_diagnostic()
     }
@@ -1,17 +1,55 @@
-use command_error::CommandExt;
-use miette::IntoDiagnostic;
-
 use super::Nix;
 use crate::flake::Flake;
+use crate::flake_lock::FlakeLock;
+use crate::flake_lock::InputChange;
 
 impl Nix {
-    /// Update a flake lockfile.
-    pub fn flake_update(&self, flake: &Flake) -> miette::Result<()> {
-        tracing::info!("Updating flake inputs");
-        self.command(&["flake", "update", "--flake"])
-            .arg(flake.to_string())
-            .status_checked()
-            .into_diagnostic()
-            .map(|_| ())
+    /// Update a flake lockfile and return the inputs that changed.
+    ///
+    /// If `inputs` is empty, all inputs are updated.
+    #[tracing::instrument(level = "debug", skip_all, fields(%flake, ?inputs))]
+    pub fn flake_update(
+        &self,
+        flake: &Flake,
+        inputs: &[String],
+    ) -> miette::Result<Vec<InputChange>> {
+        let old_locks = self.flake_metadata(flake)?.locks;
+
+        if let Some(old_locks) = &old_locks {
+            old_locks.validate_root_inputs(inputs)?;
+        }
+
+        if inputs.is_empty() {
+            tracing::info!("Updating flake inputs");
+        } else {
+            tracing::info!("Updating flake inputs: {}", inputs.join(", "));
+        }
+        if self.capabilities().flake_update_inputs {
+            self.run(
+                self.command(&["flake", "update", "--flake"])
+                    .arg(flake.to_string())
+                    .args(inputs),
+            )?;
+        } else if inputs.is_empty() {
+            self.run(self.command(&["flake", "update"]).arg(flake.to_string()))?;
+        } else {
+            self.run(
+                self.command(&["flake", "lock"])
+                    .arg(flake.to_string())
+                    .args(inputs.iter().flat_map(|input| ["--update-input", input])),
+            )?;
+        }
+
+        let new_locks = self.flake_metadata(flake)?.locks;
+
+        match (old_locks, new_locks) {
+            (Some(old_locks), Some(new_locks)) => Ok(old_locks.diff(&new_locks)),
+            // The flake didn't have a lockfile before.
+            (None, Some(new_locks)) => Ok(FlakeLock::default().diff(&new_locks)),
+            _ => {
+                tracing::warn!("`nix flake metadata` didn't report `flake.lock` contents; unable to determine which inputs changed");
+                Ok(Vec::new())
+            }
+        }
     }
 }
@@ -0,0 +1,119 @@
+use camino::Utf8Path;
+use miette::Context;
+use miette::IntoDiagnostic;
+
+use super::profile_generation_link;
+use super::Nix;
+
+/// Parse the generation number from a generation link name, like `profile-7-link`.
+fn generation_number(link_name: &str) -> Option<u64> {
+    link_name
+        .strip_suffix("-link")?
+        .rsplit('-')
+        .next()?
+        .parse()
+        .ok()
+}
+
+impl Nix {
+    /// The number of the profile's current generation, if it has one.
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub fn profile_generation(&self) -> Option<u64> {
+        let profile = self.profile_path()?;
+        match current_generation(&profile) {
+            Ok(generation) => generation,
+            Err(err) => {
+                tracing::debug!("Failed to find current generation of {profile}: {err:?}");
+                None
+            }
+        }
+    }
+
+    /// Switch the profile back to an earlier generation.
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub fn rollback_to(&self, generation: u64) -> miette::Result<()> {
+        self.run(
+            self.command(&["profile", "rollback"])
+                .args(["--to", &generation.to_string()]),
+        )
+        .map(|_| ())
+    }
+}
+
+fn current_generation(profile: &Utf8Path) -> miette::Result<Option<u64>> {
+    let link = profile_generation_link(profile)?;
+    let target = link
+        .read_link_utf8()
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to read profile symlink {link}"))?;
+    Ok(target.file_name().and_then(generation_number))
+}
+
+impl Nix {
+    /// The numbers of the profile's generations, oldest first.
+    pub fn profile_generations(&self) -> miette::Result<Vec<u64>> {
+        let Some(profile) = self.profile_path() else {
+            return Ok(Vec::new());
+        };
+        let link = profile_generation_link(&profile)?;
+        let (Some(directory), Some(name)) = (link.parent(), link.file_name()) else {
+            return Ok(Vec::new());
+        };
+
+        let mut generations = directory
+            .read_dir_utf8()
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to list {directory}"))?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                entry
+                    .file_name()
+                    .strip_prefix(name)?
+                    .strip_prefix('-')
+                    .and_then(generation_number)
+            })
+            .collect::<Vec<_>>();
+        generations.sort();
+        Ok(generations)
+    }
+
+    /// Delete the profile's old generations, or only those older than `older_than` (like
+    /// `30d`). The current generation is never deleted.
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub fn wipe_history(&self, older_than: Option<&str>) -> miette::Result<()> {
+        let mut command = self.command(&["profile", "wipe-history"]);
+        if let Some(older_than) = older_than {
+            command.args(["--older-than", older_than]);
+        }
+        self.run(&mut command).map(|_| ())
+    }
+
+    /// Delete all but the newest `count` generations of the profile.
+    ///
+    /// `nix profile wipe-history` can't do this, but `nix-env` can.
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub fn keep_generations(&self, count: u32) -> miette::Result<()> {
+        let mut command = self.nix_env_command();
+        if let Some(profile) = &self.profile {
+            command.args(["--profile", profile.as_str()]);
+        }
+        command.args(["--delete-generations", &format!("+{count}")]);
+        self.run(&mut command).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generation_number() {
+        assert_eq!(generation_number("profile-7-link"), Some(7));
+        assert_eq!(generation_number("home-manager-123-link"), Some(123));
+        // `profile_generations` strips the profile name first.
+        assert_eq!(generation_number("42-link"), Some(42));
+        assert_eq!(generation_number("profile"), None);
+        assert_eq!(generation_number("profile-link"), None);
+        assert_eq!(generation_number("profile-7"), None);
+    }
+}
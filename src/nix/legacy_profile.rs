@@ -0,0 +1,94 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+
+use camino::Utf8Path;
+use camino::Utf8PathBuf;
+use miette::miette;
+use miette::Context;
+use miette::IntoDiagnostic;
+use serde_json::Value as Json;
+
+use crate::symlinks::symlink_chain;
+
+use super::Nix;
+
+/// A package installed with `nix-env`.
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct LegacyPackage {
+    /// Like `hello-2.12.1`.
+    pub name: String,
+    /// Map from output names to store paths.
+    #[serde(default)]
+    pub outputs: BTreeMap<String, Option<Utf8PathBuf>>,
+}
+
+impl LegacyPackage {
+    pub fn store_paths(&self) -> impl Iterator<Item = &Utf8Path> {
+        self.outputs.values().flatten().map(|path| path.as_path())
+    }
+}
+
+/// Packages installed in a `nix-env` profile.
+pub struct LegacyPackages {
+    /// The raw `nix-env --query --json` output, for recording.
+    pub json: Json,
+    pub packages: Vec<LegacyPackage>,
+}
+
+/// Is the profile at `path` managed by `nix-env` rather than `nix profile`?
+pub fn is_legacy_profile(path: &Utf8Path) -> bool {
+    path.join("manifest.nix").exists() && !path.join("manifest.json").exists()
+}
+
+/// Find the symlink which points to the current generation of the profile at `path`.
+///
+/// `~/.nix-profile` usually links to a profile like `~/.local/state/nix/profiles/profile`,
+/// which links to the current generation, like `profile-7-link`.
+pub fn profile_generation_link(path: &Utf8Path) -> miette::Result<Utf8PathBuf> {
+    for link in symlink_chain(path) {
+        let (link, target) = link.wrap_err_with(|| format!("Failed to resolve profile {path}"))?;
+        if target
+            .file_name()
+            .is_some_and(|name| name.ends_with("-link"))
+        {
+            return Ok(link);
+        }
+    }
+    Err(miette!("Failed to resolve profile {path}"))
+}
+
+impl Nix {
+    /// Query the packages installed with `nix-env`.
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub fn legacy_packages(&self) -> miette::Result<LegacyPackages> {
+        let mut command = self.nix_env_command();
+        command.args(["--query", "--installed", "--out-path", "--json"]);
+        if let Some(profile) = &self.profile {
+            command.args(["--profile", profile.as_str()]);
+        }
+        let json_output = self.run(&mut command)?;
+
+        let json: Json = serde_json::from_str(&json_output)
+            .into_diagnostic()
+            .wrap_err("Failed to parse `nix-env --query --json` output")?;
+        let packages: BTreeMap<String, LegacyPackage> = serde_json::from_value(json.clone())
+            .into_diagnostic()
+            .wrap_err("Failed to parse `nix-env --query --json` output")?;
+
+        Ok(LegacyPackages {
+            json,
+            packages: packages.into_values().collect(),
+        })
+    }
+
+    /// The store paths in the closures of `paths`.
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub fn closure(&self, paths: &BTreeSet<Utf8PathBuf>) -> miette::Result<BTreeSet<Utf8PathBuf>> {
+        let stdout = self.run(
+            self.command(&["path-info", "--recursive"])
+                .args(paths.iter().map(|path| path.as_str())),
+        )?;
+
+        Ok(stdout.lines().map(Utf8PathBuf::from).collect())
+    }
+}
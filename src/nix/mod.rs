@@ -1,17 +1,42 @@
+use std::ffi::OsStr;
 use std::process::Command;
+use std::sync::Arc;
 
 mod profile_list;
+use camino::Utf8Path;
 use camino::Utf8PathBuf;
+use command_error::CommandExt;
+use miette::miette;
 use miette::Context;
 use miette::IntoDiagnostic;
 pub use profile_list::ProfileList;
+pub use profile_list::ProfileListV3Element;
+pub use profile_list::DEFAULT_PRIORITY;
 
 mod flake_metadata;
+pub use flake_metadata::report_dirty;
+pub use flake_metadata::FlakeMetadata;
 pub use flake_metadata::ResolvedFlake;
 use tap::TryConv;
 
+use crate::timings::CommandSpanExt;
+
 mod build;
+mod environment;
+mod eval;
+mod legacy_profile;
+pub use environment::Environment;
+pub use environment::NixEnvironment;
+pub use legacy_profile::is_legacy_profile;
+pub use legacy_profile::profile_generation_link;
 mod flake_update;
+mod generation;
+mod run;
+mod staged_profile;
+mod store_gc;
+mod version;
+pub use version::NixCapabilities;
+pub use version::NixVersion;
 
 #[derive(Debug, Clone)]
 pub struct Nix {
@@ -19,12 +44,30 @@ pub struct Nix {
     program: Utf8PathBuf,
     /// Path to the current profile.
     profile: Option<Utf8PathBuf>,
+    /// Directory to write `nix` output logs to.
+    log_dir: Option<Utf8PathBuf>,
+    /// Show build progress on the terminal.
+    progress: bool,
+    /// The environment to run `nix` in, or `None` to inherit ours.
+    environment: Option<Arc<Environment>>,
+    /// The installed Nix version.
+    version: NixVersion,
 }
 
 impl Nix {
-    pub fn new() -> miette::Result<Self> {
-        let mut program = which::which_global("nix")
+    pub fn new(environment: &NixEnvironment) -> miette::Result<Self> {
+        let environment = environment.capture()?;
+        let path = match &environment {
+            Some(environment) => environment.get(OsStr::new("PATH")).cloned(),
+            None => std::env::var_os("PATH"),
+        };
+        let mut program = which::which_in_global("nix", path)
             .into_diagnostic()
+            .and_then(|mut paths| {
+                paths
+                    .next()
+                    .ok_or_else(|| miette!("No `nix` executable in `$PATH`"))
+            })
             .wrap_err("Could not find `nix` executable")?
             .try_conv::<Utf8PathBuf>()
             .into_diagnostic()?;
@@ -36,20 +79,85 @@ impl Nix {
                 .wrap_err_with(|| format!("Failed to read `nix` symlink: {program:?}"))?;
         }
         tracing::debug!(path = %program, "Found `nix`");
+
+        let version = base_command(&program, environment.as_ref())
+            .arg("--version")
+            .in_span(|command| command.output_checked_utf8())
+            .into_diagnostic()?
+            .stdout;
+        let version = NixVersion::parse(&version)?;
+        tracing::debug!(%version, capabilities = ?version.capabilities(), "Detected Nix version");
+        version.check_supported()?;
+
         Ok(Self {
             program,
             profile: None,
+            log_dir: None,
+            progress: false,
+            environment: environment.map(Arc::new),
+            version,
         })
     }
 
+    /// The path of the profile `nix profile` operates on, if we can find it.
+    ///
+    /// This is `--profile` if given, otherwise `~/.nix-profile`, or
+    /// `$XDG_STATE_HOME/nix/profile` if Nix is configured with `use-xdg-base-directories`.
+    pub fn profile_path(&self) -> Option<Utf8PathBuf> {
+        if let Some(profile) = &self.profile {
+            return Some(profile.clone());
+        }
+
+        let base_dirs = directories::BaseDirs::new()?;
+        let home = Utf8Path::from_path(base_dirs.home_dir())?;
+        let nix_profile = home.join(".nix-profile");
+        if nix_profile.exists() {
+            return Some(nix_profile);
+        }
+
+        let state_dir = match base_dirs.state_dir() {
+            Some(state_dir) => Utf8Path::from_path(state_dir)?.to_path_buf(),
+            None => home.join(".local/state"),
+        };
+        let xdg_profile = state_dir.join("nix/profile");
+        xdg_profile.exists().then_some(xdg_profile)
+    }
+
+    /// The installed Nix implementation and version.
+    pub fn version(&self) -> NixVersion {
+        self.version
+    }
+
+    /// The command-line features supported by the installed Nix.
+    pub fn capabilities(&self) -> NixCapabilities {
+        self.version.capabilities()
+    }
+
     pub fn with_profile(mut self, profile: Option<Utf8PathBuf>) -> Self {
         self.profile = profile;
         self
     }
 
+    pub fn with_log_dir(mut self, log_dir: Option<Utf8PathBuf>) -> Self {
+        self.log_dir = log_dir;
+        self
+    }
+
+    pub fn with_progress(mut self, progress: bool) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// A `nix-env` command, for legacy profiles.
+    pub fn nix_env_command(&self) -> Command {
+        base_command(
+            &self.program.with_file_name("nix-env"),
+            self.environment.as_deref(),
+        )
+    }
+
     pub fn command(&self, subcommand: &[&str]) -> Command {
-        // TODO: Should run in `sh` after sourcing the Nix profile.
-        let mut command = Command::new(&self.program);
+        let mut command = base_command(&self.program, self.environment.as_deref());
         command.args(["--extra-experimental-features", "nix-command flakes"]);
         command.args(subcommand);
         #[allow(clippy::single_match)]
@@ -65,3 +173,13 @@ impl Nix {
         command
     }
 }
+
+/// A command to run `program` in `environment`.
+fn base_command(program: &Utf8Path, environment: Option<&Environment>) -> Command {
+    let mut command = Command::new(program);
+    if let Some(environment) = environment {
+        command.env_clear();
+        command.envs(environment.iter());
+    }
+    command
+}
@@ -1,37 +1,134 @@
 use std::collections::BTreeMap;
 
 use camino::Utf8PathBuf;
-use command_error::CommandExt;
 use miette::miette;
+use miette::Context;
 use miette::IntoDiagnostic;
 use serde_json::Value as Json;
 
+use super::is_legacy_profile;
 use super::Nix;
 
 impl Nix {
+    #[tracing::instrument(level = "debug", skip(self))]
     pub fn profile_list(&self) -> miette::Result<ProfileList> {
-        let json_output = self
-            .command(&["profile", "list"])
-            .arg("--json")
-            .output_checked_utf8()
-            .into_diagnostic()?
-            .stdout;
+        let error = if self.capabilities().profile_list_json {
+            match self.run(self.command(&["profile", "list"]).arg("--json")) {
+                Ok(json_output) => {
+                    match ProfileList::from_json(&json_output, "nix profile list --json") {
+                        Ok(profile_list) => return Ok(profile_list),
+                        Err(err) => err,
+                    }
+                }
+                Err(err) => err,
+            }
+        } else {
+            miette!("{} can't run `nix profile list --json`", self.version())
+        };
 
-        let data: ProfileListUnknown = serde_json::from_str(&json_output).into_diagnostic()?;
+        let profile = match self.profile_path() {
+            Some(profile) => profile,
+            None => return Err(error),
+        };
 
-        match data.version {
-        1..=2 => {
-            let data: ProfileListV2 = serde_json::from_value(data.rest).into_diagnostic()?;
-            Ok(ProfileList::V2(data.elements))
+        if is_legacy_profile(&profile) {
+            tracing::debug!("{error:?}");
+            return Err(miette!(
+                help = "Run `home-mangler migrate-profile` to convert it",
+                "{profile} is managed by `nix-env`, so `nix profile` can't use it"
+            ));
+        }
+
+        // Maybe this version of Nix can't print JSON; try reading the profile directly.
+        let manifest_path = profile.join("manifest.json");
+        if self.capabilities().profile_list_json {
+            tracing::warn!(
+                "Failed to list profile with `nix profile list --json`; reading {manifest_path} instead"
+            );
+        } else {
+            tracing::debug!("Reading {manifest_path}");
         }
-        3 => {
-            let data: ProfileListV3 = serde_json::from_value(data.rest).into_diagnostic()?;
-            Ok(ProfileList::V3(data.elements))
+        tracing::debug!("{error:?}");
+
+        let manifest = std::fs::read_to_string(&manifest_path)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to read {manifest_path}"))
+            .map_err(|err| err.wrap_err(error))?;
+        ProfileList::from_json(&manifest, manifest_path.as_str())
+    }
+}
+
+impl ProfileList {
+    /// Parse `nix profile list --json` output or a profile's `manifest.json`, which share a
+    /// format.
+    ///
+    /// `source` describes where the JSON came from, for error messages.
+    fn from_json(json: &str, source: &str) -> miette::Result<Self> {
+        let data: ProfileListUnknown = serde_json::from_str(json)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to parse `{source}`"))?;
+
+        match data.version {
+            1..=2 => {
+                let data: ProfileListV2 = serde_json::from_value(data.rest)
+                    .into_diagnostic()
+                    .wrap_err_with(|| format!("Failed to parse `{source}`"))?;
+                Ok(ProfileList::V2(data.elements))
+            }
+            3 => {
+                let data: ProfileListV3 = serde_json::from_value(data.rest)
+                    .into_diagnostic()
+                    .wrap_err_with(|| format!("Failed to parse `{source}`"))?;
+                Ok(ProfileList::V3(data.elements))
+            }
+            version => {
+                tracing::warn!(
+                    "Unknown `{source}` version {version}; I only know how to interpret versions 1 through 3. Reading it anyways, but results may be incorrect"
+                );
+                Self::from_unknown_version(data.rest)
+                    .wrap_err_with(|| format!("Failed to parse `{source}` version {version}"))
+            }
         }
-        version => {
-            Err(miette!("Unknown `nix profile list --json` output version {version}; I only know how to interpret output for versions 1 through 3"))
+    }
+
+    /// Best-effort parsing for versions we don't know about.
+    ///
+    /// Elements are a list (like versions 1-2) or a map from names (like version 3), and we read
+    /// whichever fields we recognize.
+    fn from_unknown_version(mut data: Json) -> miette::Result<Self> {
+        let elements = data
+            .get_mut("elements")
+            .map(Json::take)
+            .ok_or_else(|| miette!("Missing `elements` field"))?;
+
+        match elements {
+            Json::Array(elements) => Ok(ProfileList::V2(
+                elements
+                    .into_iter()
+                    .enumerate()
+                    .filter_map(|(i, element)| parse_unknown_element(&i.to_string(), element))
+                    .collect(),
+            )),
+            Json::Object(elements) => Ok(ProfileList::V3(
+                elements
+                    .into_iter()
+                    .filter_map(|(name, element)| {
+                        parse_unknown_element(&name, element).map(|element| (name, element))
+                    })
+                    .collect(),
+            )),
+            _ => Err(miette!("`elements` is neither a list nor an object")),
         }
     }
+}
+
+fn parse_unknown_element(name: &str, element: Json) -> Option<ProfileListV3Element> {
+    match serde_json::from_value(element) {
+        Ok(element) => Some(element),
+        Err(err) => {
+            tracing::warn!("Skipping unreadable profile element {name}: {err}");
+            None
+        }
     }
 }
 
@@ -46,7 +143,7 @@ pub enum ProfileList {
 
 #[derive(serde::Deserialize)]
 struct ProfileListUnknown {
-    version: u8,
+    version: u64,
     #[serde(flatten)]
     rest: Json,
 }
@@ -67,12 +164,15 @@ struct ProfileListV3 {
 #[allow(dead_code)]
 pub struct ProfileListV3Element {
     /// How is an element 'deactivated'?
+    #[serde(default = "default_active")]
     pub active: bool,
 
     /// 5
+    #[serde(default = "default_priority")]
     pub priority: u16,
 
     /// `["/nix/store/dccm0y9xpz85sm9gsfb0n7rs07cp4l7p-home-mangler-packages"]`.
+    #[serde(default)]
     pub store_paths: Vec<Utf8PathBuf>,
 
     /// `git+file:///Users/wiggles/.dotfiles?dir=config/home-mangler`
@@ -95,3 +195,91 @@ pub struct ProfileListV3Element {
     /// Doesn't seem to include the default output `out`. Or maybe that's only if it's `null`?
     pub outputs: Option<Vec<String>>,
 }
+
+fn default_active() -> bool {
+    true
+}
+
+/// The default priority for `nix profile install`.
+pub const DEFAULT_PRIORITY: u16 = 5;
+
+fn default_priority() -> u16 {
+    DEFAULT_PRIORITY
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STORE_PATH: &str = "/nix/store/dccm0y9xpz85sm9gsfb0n7rs07cp4l7p-home-mangler-packages";
+
+    fn names_and_paths(profile: &ProfileList) -> Vec<(String, Vec<Utf8PathBuf>)> {
+        profile
+            .elements()
+            .into_iter()
+            .map(|(name, element)| (name, element.store_paths.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn test_from_unknown_version_object() {
+        let profile = ProfileList::from_json(
+            &format!(
+                r#"{{
+                    "version": 4,
+                    "elements": {{
+                        "home-mangler-packages": {{
+                            "active": true,
+                            "priority": 5,
+                            "storePaths": ["{STORE_PATH}"],
+                            "url": "git+file:///Users/wiggles/.dotfiles?dir=config/home-mangler",
+                            "attrPath": "home-mangler.grandiflora.packages",
+                            "newField": {{ "unknown": true }}
+                        }},
+                        "broken": {{ "storePaths": 7 }}
+                    }}
+                }}"#
+            ),
+            "test",
+        )
+        .unwrap();
+
+        assert!(matches!(profile, ProfileList::V3(_)));
+        assert_eq!(
+            names_and_paths(&profile),
+            vec![(
+                "home-mangler-packages".to_owned(),
+                vec![Utf8PathBuf::from(STORE_PATH)]
+            )]
+        );
+        let (_, element) = &profile.elements()[0];
+        assert_eq!(
+            element.attr_path.as_deref(),
+            Some("home-mangler.grandiflora.packages")
+        );
+    }
+
+    #[test]
+    fn test_from_unknown_version_array() {
+        let profile = ProfileList::from_json(
+            &format!(r#"{{ "version": 4, "elements": [{{ "storePaths": ["{STORE_PATH}"] }}] }}"#),
+            "test",
+        )
+        .unwrap();
+
+        assert!(matches!(profile, ProfileList::V2(_)));
+        assert_eq!(
+            names_and_paths(&profile),
+            vec![("0".to_owned(), vec![Utf8PathBuf::from(STORE_PATH)])]
+        );
+        let (_, element) = &profile.elements()[0];
+        assert!(element.active);
+        assert_eq!(element.priority, DEFAULT_PRIORITY);
+    }
+
+    #[test]
+    fn test_from_unknown_version_invalid() {
+        assert!(ProfileList::from_json(r#"{ "version": 4 }"#, "test").is_err());
+        assert!(ProfileList::from_json(r#"{ "version": 4, "elements": 1 }"#, "test").is_err());
+    }
+}
@@ -0,0 +1,406 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Write;
+use std::process::Command;
+use std::process::Stdio;
+use std::sync::OnceLock;
+
+use camino::Utf8Path;
+use camino::Utf8PathBuf;
+use command_error::CommandExt;
+use miette::miette;
+use miette::Context;
+use miette::IntoDiagnostic;
+use owo_colors::OwoColorize;
+use owo_colors::Stream;
+
+use crate::timings::display_args;
+use crate::timings::display_command;
+use crate::timings::CommandSpanExt;
+
+use super::Nix;
+
+/// How many lines of output (and of a failing derivation's log) to include in errors.
+const FAILURE_LOG_LINES: usize = 25;
+
+/// How many runs' worth of `nix` logs to keep on disk.
+const MAX_NIX_LOG_RUNS: usize = 20;
+
+/// This run's directory for `nix` logs, like `20241019T123456.789`.
+///
+/// Every command run by one invocation of home-mangler logs to the same directory, so old logs
+/// are removed a whole run at a time.
+static RUN_LOG_DIR: OnceLock<String> = OnceLock::new();
+
+/// The output of a successful `nix` command.
+pub struct NixOutput {
+    pub stdout: String,
+    /// The last lines of standard error.
+    pub stderr_tail: Vec<String>,
+}
+
+impl Nix {
+    /// Run a `nix` command and return its standard output.
+    ///
+    /// Standard error is written to a log file (if [`Nix::with_log_dir`] was used) and parsed for
+    /// build progress. Warnings are logged, build logs are shown at the `debug` level, and if
+    /// the command fails, the end of its output and the failing derivation's log are attached
+    /// to the error.
+    pub fn run(&self, command: &mut Command) -> miette::Result<String> {
+        self.run_output(command).map(|output| output.stdout)
+    }
+
+    /// Like [`Nix::run`], but also return the end of standard error, for commands which report
+    /// their results there.
+    pub fn run_output(&self, command: &mut Command) -> miette::Result<NixOutput> {
+        command.in_span(|command| self.run_inner(command))
+    }
+
+    fn run_inner(&self, command: &mut Command) -> miette::Result<NixOutput> {
+        let display = display_command(command);
+        let log_path = self.log_path(command);
+        let mut log = match &log_path {
+            Some(path) => Some(BufWriter::new(
+                File::create(path)
+                    .into_diagnostic()
+                    .wrap_err_with(|| format!("Failed to create log file {path}"))?,
+            )),
+            None => None,
+        };
+
+        let mut child = command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to execute `{display}`"))?;
+
+        let mut stdout = child.stdout.take().expect("stdout is piped");
+        let stdout = std::thread::spawn(move || {
+            let mut buffer = String::new();
+            stdout.read_to_string(&mut buffer).map(|_| buffer)
+        });
+
+        let stderr = child.stderr.take().expect("stderr is piped");
+        let mut progress = Progress::new(self.progress);
+        let mut tail = VecDeque::with_capacity(FAILURE_LOG_LINES);
+        for line in BufReader::new(stderr).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(err) => {
+                    progress.clear();
+                    // We've stopped reading stderr, so the child could block writing to it
+                    // forever; kill it so that it can be reaped instead of left running.
+                    if let Err(kill_err) = child.kill() {
+                        tracing::debug!("Failed to kill command after a read error: {kill_err}");
+                    }
+                    if let Err(wait_err) = child.wait() {
+                        tracing::debug!(
+                            "Failed to wait for command after a read error: {wait_err}"
+                        );
+                    }
+                    let _ = stdout.join();
+                    return Err(err)
+                        .into_diagnostic()
+                        .wrap_err_with(|| format!("Failed to read output of `{display}`"));
+                }
+            };
+
+            if let Some(log) = &mut log {
+                // Don't fail the whole command if we can't write the log.
+                if let Err(err) = writeln!(log, "{line}") {
+                    tracing::debug!("Failed to write to log file: {err}");
+                }
+            }
+
+            progress.line(&line);
+
+            if tail.len() == FAILURE_LOG_LINES {
+                tail.pop_front();
+            }
+            tail.push_back(line);
+        }
+        progress.clear();
+
+        if let Some(mut log) = log {
+            if let Err(err) = log.flush() {
+                tracing::debug!("Failed to write to log file: {err}");
+            }
+        }
+
+        let status = child
+            .wait()
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to wait for `{display}`"))?;
+        let stdout = stdout
+            .join()
+            .map_err(|_| miette!("Thread reading output of `{display}` panicked"))?
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to read output of `{display}`"))?;
+
+        if status.success() {
+            return Ok(NixOutput {
+                stdout,
+                stderr_tail: tail.into(),
+            });
+        }
+
+        let mut message = format!("`{display}` failed: {status}");
+        if !tail.is_empty() {
+            message.push_str(&format!(
+                "\nLast {} lines of output:\n{}",
+                tail.len(),
+                indent(tail.iter())
+            ));
+        }
+        if let Some(drv) = &progress.failed_derivation {
+            if let Some(drv_log) = self.derivation_log_tail(drv) {
+                message.push_str(&format!("\nLog for {drv}:\n{drv_log}"));
+            }
+        }
+
+        Err(match log_path {
+            Some(log_path) => miette!(help = format!("Full log: {log_path}"), "{message}"),
+            None => miette!("{message}"),
+        })
+    }
+
+    /// The last lines of the build log for `drv`, from `nix log`.
+    fn derivation_log_tail(&self, drv: &str) -> Option<String> {
+        let stdout = match self.command(&["log"]).arg(drv).output_checked_utf8() {
+            Ok(output) => output.stdout,
+            Err(err) => {
+                tracing::debug!("Failed to get log for {drv}: {err}");
+                return None;
+            }
+        };
+
+        let lines = stdout.lines().collect::<Vec<_>>();
+        if lines.is_empty() {
+            return None;
+        }
+        let start = lines.len().saturating_sub(FAILURE_LOG_LINES);
+        Some(indent(lines[start..].iter()))
+    }
+
+    /// A new log file path for `command` in this run's log directory. The first time this is
+    /// called, logs from old runs are removed.
+    fn log_path(&self, command: &Command) -> Option<Utf8PathBuf> {
+        let log_dir = self.log_dir.as_ref()?;
+        let mut new_run = false;
+        let run = RUN_LOG_DIR.get_or_init(|| {
+            new_run = true;
+            jiff::Zoned::now().strftime("%Y%m%dT%H%M%S%.3f").to_string()
+        });
+        if new_run {
+            remove_old_logs(log_dir);
+        }
+        let run_dir = log_dir.join(run);
+        if let Err(err) = std::fs::create_dir_all(&run_dir) {
+            tracing::debug!("Failed to create directory {run_dir}: {err}");
+            return None;
+        }
+
+        // Like `nix-flake-metadata`, `nix-build`, or `nix-env`.
+        let program = Utf8Path::new(command.get_program().to_str().unwrap_or("nix"))
+            .file_name()
+            .unwrap_or("nix");
+        let subcommand = std::iter::once(program)
+            .chain(
+                display_args(command)
+                    .map_while(|arg| arg.to_str().filter(|arg| !arg.starts_with('-')))
+                    .take(2),
+            )
+            .collect::<Vec<_>>()
+            .join("-");
+        let timestamp = jiff::Zoned::now().strftime("%Y%m%dT%H%M%S%.3f");
+        Some(run_dir.join(format!("{timestamp}-{subcommand}.log")))
+    }
+}
+
+/// Remove all but the newest [`MAX_NIX_LOG_RUNS`] runs' logs in `log_dir`, making room for a
+/// new run. Log files from before logs were grouped by run count as a run each.
+fn remove_old_logs(log_dir: &Utf8Path) {
+    let mut logs = match log_dir.read_dir_utf8() {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.into_path())
+            .filter(|path| path.is_dir() || path.extension() == Some("log"))
+            .collect::<Vec<_>>(),
+        Err(err) => {
+            tracing::debug!("Failed to list {log_dir}: {err}");
+            return;
+        }
+    };
+
+    // Names start with timestamps, so this sorts oldest first.
+    logs.sort();
+    let excess = (logs.len() + 1).saturating_sub(MAX_NIX_LOG_RUNS);
+    for path in &logs[..excess] {
+        let result = if path.is_dir() {
+            std::fs::remove_dir_all(path)
+        } else {
+            std::fs::remove_file(path)
+        };
+        if let Err(err) = result {
+            tracing::debug!("Failed to remove old log {path}: {err}");
+        }
+    }
+}
+
+fn indent<'a>(lines: impl Iterator<Item = impl AsRef<str> + 'a>) -> String {
+    lines
+        .map(|line| format!("  {}", line.as_ref()))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Tracks build progress from `nix`'s standard error.
+struct Progress {
+    /// Draw a status line on the terminal.
+    enabled: bool,
+    /// Is a status line currently drawn?
+    drawn: bool,
+    /// How many derivations `nix` said it would build.
+    total: Option<usize>,
+    /// How many derivations have started building.
+    started: usize,
+    /// The name of the derivation most recently started.
+    current: Option<String>,
+    /// The derivation which failed to build, if any.
+    failed_derivation: Option<String>,
+}
+
+impl Progress {
+    fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            drawn: false,
+            total: None,
+            started: 0,
+            current: None,
+            failed_derivation: None,
+        }
+    }
+
+    fn line(&mut self, line: &str) {
+        if let Some(count) = line
+            .strip_prefix("these ")
+            .and_then(|rest| rest.strip_suffix(" derivations will be built:"))
+        {
+            self.total = count.parse().ok();
+        } else if line == "this derivation will be built:" {
+            self.total = Some(1);
+        } else if let Some(drv) = line
+            .strip_prefix("building '")
+            .and_then(|rest| rest.strip_suffix("'..."))
+        {
+            self.started += 1;
+            let name = derivation_name(drv).to_owned();
+            match self.total {
+                Some(total) => tracing::debug!("Building {name} ({}/{total})", self.started),
+                None => tracing::debug!("Building {name}"),
+            }
+            self.current = Some(name);
+            self.draw();
+        } else if let Some(message) = line.strip_prefix("warning: ") {
+            self.clear();
+            tracing::warn!("{message}");
+            self.draw();
+        } else if line.starts_with("error:") {
+            if self.failed_derivation.is_none() && line.contains("build") {
+                self.failed_derivation = quoted_derivation(line).map(ToOwned::to_owned);
+            }
+            tracing::debug!("{line}");
+        } else {
+            tracing::debug!("{line}");
+        }
+    }
+
+    fn draw(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        let current = match &self.current {
+            Some(current) => current,
+            None => return,
+        };
+        let count = match self.total {
+            Some(total) => format!("[{}/{total}]", self.started),
+            None => format!("[{}]", self.started),
+        };
+        eprint!(
+            "\r\x1b[2K{} Building {current}",
+            count.if_supports_color(Stream::Stderr, |text| text.cyan())
+        );
+        self.drawn = true;
+    }
+
+    fn clear(&mut self) {
+        if self.drawn {
+            eprint!("\r\x1b[2K");
+            self.drawn = false;
+        }
+    }
+}
+
+/// The name of a derivation, like `hello-2.12.1` for `/nix/store/...-hello-2.12.1.drv`.
+fn derivation_name(drv: &str) -> &str {
+    let name = drv.rsplit('/').next().unwrap_or(drv);
+    let name = name.strip_suffix(".drv").unwrap_or(name);
+    match name.split_once('-') {
+        Some((_hash, name)) => name,
+        None => name,
+    }
+}
+
+/// The first quoted `.drv` path in `line`, like in
+/// `error: builder for '/nix/store/...-hello.drv' failed with exit code 1`.
+fn quoted_derivation(line: &str) -> Option<&str> {
+    line.split('\'')
+        .find(|part| part.starts_with("/nix/store/") && part.ends_with(".drv"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remove_old_logs() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+        // Logs from before they were grouped by run.
+        for minute in 0..5 {
+            std::fs::write(
+                dir.join(format!("20240101T00{minute:02}00.000-nix-build.log")),
+                "",
+            )
+            .unwrap();
+        }
+        for day in 1..=MAX_NIX_LOG_RUNS {
+            let run = dir.join(format!("202402{day:02}T000000.000"));
+            std::fs::create_dir(&run).unwrap();
+            std::fs::write(run.join("20240201T000000.000-nix-build.log"), "").unwrap();
+        }
+        std::fs::write(dir.join("README"), "").unwrap();
+
+        remove_old_logs(dir);
+
+        let mut remaining = dir
+            .read_dir_utf8()
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_owned())
+            .collect::<Vec<_>>();
+        remaining.sort();
+        let mut expected = (2..=MAX_NIX_LOG_RUNS)
+            .map(|day| format!("202402{day:02}T000000.000"))
+            .collect::<Vec<_>>();
+        expected.push("README".to_owned());
+        assert_eq!(remaining, expected);
+    }
+}
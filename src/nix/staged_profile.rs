@@ -0,0 +1,110 @@
+use camino::Utf8Path;
+use camino::Utf8PathBuf;
+use miette::miette;
+use miette::Context;
+use miette::IntoDiagnostic;
+
+use super::profile_generation_link;
+use super::Nix;
+
+/// A temporary profile to make several changes in, before switching the real profile to the
+/// result.
+///
+/// Each `nix profile` command creates a new generation, so removing old packages and then
+/// installing new ones would leave the real profile without either in between. Making the
+/// changes here and then calling [`StagedProfile::commit`] switches the real profile in a
+/// single generation.
+pub struct StagedProfile {
+    /// Keeps the temporary directory around until we're done with it.
+    _directory: tempfile::TempDir,
+    /// The staged profile's path, like `/tmp/home-mangler-profile-XXXX/profile`.
+    path: Utf8PathBuf,
+    /// Runs `nix profile` commands on the staged profile.
+    nix: Nix,
+}
+
+impl Nix {
+    /// Stage changes to a copy of the current profile.
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub fn stage_profile(&self) -> miette::Result<StagedProfile> {
+        let staged = self.stage_new_profile()?;
+        if let Some(profile) = self.profile_path() {
+            let generation = profile
+                .canonicalize_utf8()
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to resolve profile {profile}"))?;
+            std::os::unix::fs::symlink(&generation, &staged.path)
+                .into_diagnostic()
+                .wrap_err_with(|| {
+                    format!("Failed to create symlink {} -> {generation}", staged.path)
+                })?;
+        }
+        Ok(staged)
+    }
+
+    /// Stage changes to a new, empty profile.
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub fn stage_new_profile(&self) -> miette::Result<StagedProfile> {
+        let directory = tempfile::Builder::new()
+            .prefix("home-mangler-profile-")
+            .tempdir()
+            .into_diagnostic()
+            .wrap_err("Failed to create temporary directory")?;
+        let path = Utf8Path::from_path(directory.path())
+            .ok_or_else(|| {
+                miette!(
+                    "Temporary directory isn't UTF-8: {}",
+                    directory.path().display()
+                )
+            })?
+            .join("profile");
+        let nix = self.clone().with_profile(Some(path.clone()));
+        Ok(StagedProfile {
+            _directory: directory,
+            path,
+            nix,
+        })
+    }
+
+    /// Switch the profile to a new generation containing `store_path`.
+    #[tracing::instrument(level = "debug", skip(self))]
+    fn set_profile(&self, profile: &Utf8Path, store_path: &Utf8Path) -> miette::Result<()> {
+        let mut command = self.nix_env_command();
+        command.args(["--profile", profile.as_str(), "--set", store_path.as_str()]);
+        self.run(&mut command).map(|_| ())
+    }
+}
+
+impl StagedProfile {
+    /// Runs `nix profile` commands on the staged profile.
+    pub fn nix(&self) -> &Nix {
+        &self.nix
+    }
+
+    /// Switch the profile `nix` operates on to the staged profile, creating a single new
+    /// generation.
+    ///
+    /// Returns `false` without changing anything if no changes were made to a new staged
+    /// profile.
+    pub fn commit(self, nix: &Nix) -> miette::Result<bool> {
+        if self.path.symlink_metadata().is_err() {
+            return Ok(false);
+        }
+        let store_path = self
+            .path
+            .canonicalize_utf8()
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to resolve staged profile {}", self.path))?;
+        let profile = nix.profile_path().ok_or_else(|| {
+            miette!(
+                help = "Use `--profile` to select your Nix profile",
+                "Could not find your Nix profile"
+            )
+        })?;
+        // `nix-env` creates the new generation next to the profile, so give it the profile
+        // itself, rather than a symlink to it like `~/.nix-profile`.
+        let profile = profile_generation_link(&profile)?;
+        nix.set_profile(&profile, &store_path)?;
+        Ok(true)
+    }
+}
@@ -0,0 +1,71 @@
+use super::Nix;
+
+/// What `nix store gc` deleted.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StoreGcResult {
+    pub deleted_paths: Option<u64>,
+    pub freed_bytes: Option<u64>,
+}
+
+impl StoreGcResult {
+    /// Parse the summary `nix store gc` prints at the end, like
+    /// `1234 store paths deleted, 567.89 MiB freed`.
+    fn parse(line: &str) -> Option<Self> {
+        let (paths, freed) = line.trim().split_once(" store paths deleted, ")?;
+        let (amount, unit) = freed.strip_suffix(" freed")?.split_once(' ')?;
+        let multiplier: u64 = match unit {
+            "B" | "bytes" => 1,
+            "KiB" => 1 << 10,
+            "MiB" => 1 << 20,
+            "GiB" => 1 << 30,
+            "TiB" => 1 << 40,
+            _ => return None,
+        };
+        let amount: f64 = amount.parse().ok()?;
+
+        Some(Self {
+            deleted_paths: paths.parse().ok(),
+            freed_bytes: Some((amount * multiplier as f64) as u64),
+        })
+    }
+}
+
+impl Nix {
+    /// Delete unreachable paths from the Nix store.
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub fn store_gc(&self) -> miette::Result<StoreGcResult> {
+        let output = self.run_output(&mut self.command(&["store", "gc"]))?;
+        let result = output
+            .stderr_tail
+            .iter()
+            .rev()
+            .find_map(|line| StoreGcResult::parse(line));
+        if result.is_none() {
+            tracing::debug!("Couldn't find summary in `nix store gc` output");
+        }
+        Ok(result.unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_store_gc_result_parse() {
+        let result = StoreGcResult::parse("1234 store paths deleted, 567.89 MiB freed\n").unwrap();
+        assert_eq!(result.deleted_paths, Some(1234));
+        assert_eq!(result.freed_bytes, Some(595_475_824));
+
+        let result = StoreGcResult::parse("0 store paths deleted, 0.0 KiB freed").unwrap();
+        assert_eq!(result.deleted_paths, Some(0));
+        assert_eq!(result.freed_bytes, Some(0));
+    }
+
+    #[test]
+    fn test_store_gc_result_parse_other_lines() {
+        assert!(StoreGcResult::parse("finding garbage collector roots...").is_none());
+        assert!(StoreGcResult::parse("deleting '/nix/store/abc-foo'").is_none());
+        assert!(StoreGcResult::parse("1 store paths deleted, 1.00 PiB freed").is_none());
+    }
+}
@@ -0,0 +1,309 @@
+use std::fmt::Display;
+use std::str::FromStr;
+
+use miette::miette;
+
+/// The oldest version of Nix we support.
+///
+/// Flakes and `nix profile` were added in Nix 2.4. Newer features are used when available; see
+/// [`NixCapabilities`].
+pub const MINIMUM_VERSION: Version = Version::new(2, 4, 0);
+
+/// A version number, like `2.24.10`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl Version {
+    pub const fn new(major: u32, minor: u32, patch: u32) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+        }
+    }
+}
+
+impl FromStr for Version {
+    type Err = miette::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Pre-release versions look like `2.25.0pre20241101_3b3e2ee` or `2.92.0-dev`.
+        let numeric = s
+            .split(|c: char| !c.is_ascii_digit() && c != '.')
+            .next()
+            .unwrap_or_default();
+        let mut parts = numeric.split('.').map(|part| part.parse::<u32>());
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(Ok(major)), Some(Ok(minor)), patch) => Ok(Self::new(
+                major,
+                minor,
+                patch.and_then(|patch| patch.ok()).unwrap_or(0),
+            )),
+            _ => Err(miette!("Failed to parse version number: {s:?}")),
+        }
+    }
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Which Nix implementation is installed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NixImplementation {
+    /// CppNix, the reference implementation.
+    Nix,
+    /// Lix, forked from Nix 2.18. Lix versions start at 2.90.
+    Lix,
+    /// Determinate Nix, a CppNix distribution. The version is the CppNix version it's based on.
+    Determinate,
+}
+
+impl Display for NixImplementation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NixImplementation::Nix => write!(f, "Nix"),
+            NixImplementation::Lix => write!(f, "Lix"),
+            NixImplementation::Determinate => write!(f, "Determinate Nix"),
+        }
+    }
+}
+
+/// The output of `nix --version`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NixVersion {
+    pub implementation: NixImplementation,
+    pub version: Version,
+}
+
+impl NixVersion {
+    /// Parse `nix --version` output, like `nix (Nix) 2.24.10`, `nix (Lix, like Nix) 2.91.1`, or
+    /// `nix (Determinate Nix 3.6.2) 2.29.0`.
+    pub fn parse(output: &str) -> miette::Result<Self> {
+        let output = output.trim();
+        let version = output
+            .rsplit(' ')
+            .next()
+            .ok_or_else(|| miette!("Failed to parse `nix --version` output: {output:?}"))?;
+        let version = version.parse().map_err(|err: miette::Report| {
+            err.wrap_err("Failed to parse `nix --version` output")
+        })?;
+
+        let implementation = match output
+            .split_once('(')
+            .and_then(|(_, rest)| rest.split_once(')'))
+        {
+            Some((name, _)) if name.starts_with("Lix") => NixImplementation::Lix,
+            Some((name, _)) if name.starts_with("Determinate") => NixImplementation::Determinate,
+            _ => NixImplementation::Nix,
+        };
+
+        Ok(Self {
+            implementation,
+            version,
+        })
+    }
+
+    /// Check that this version is new enough for home-mangler.
+    pub fn check_supported(&self) -> miette::Result<()> {
+        // Lix versions are all newer than our minimum.
+        if self.implementation != NixImplementation::Lix && self.version < MINIMUM_VERSION {
+            return Err(miette!(
+                help = "Upgrade Nix, or set `environment` to find a newer `nix`",
+                "{self} is too old; home-mangler requires Nix {MINIMUM_VERSION} or newer"
+            ));
+        }
+        Ok(())
+    }
+
+    /// The command-line features this version supports.
+    pub fn capabilities(&self) -> NixCapabilities {
+        match self.implementation {
+            // Lix is forked from Nix 2.18.
+            NixImplementation::Lix => NixCapabilities {
+                build_print_out_paths: true,
+                profile_install_priority: true,
+                profile_list_json: true,
+                flake_update_inputs: false,
+                profile_add: false,
+            },
+            NixImplementation::Nix | NixImplementation::Determinate => NixCapabilities {
+                build_print_out_paths: self.version >= Version::new(2, 8, 0),
+                profile_install_priority: self.version >= Version::new(2, 14, 0),
+                profile_list_json: self.version >= Version::new(2, 17, 0),
+                flake_update_inputs: self.version >= Version::new(2, 19, 0),
+                profile_add: self.version >= Version::new(2, 30, 0),
+            },
+        }
+    }
+}
+
+impl Display for NixVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.implementation, self.version)
+    }
+}
+
+/// Differences in the command-line interface between Nix versions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NixCapabilities {
+    /// `nix build --print-out-paths` prints the built store paths (Nix 2.8).
+    ///
+    /// Otherwise, they're read from `nix build --json`.
+    pub build_print_out_paths: bool,
+    /// `nix profile install --priority` (Nix 2.14).
+    pub profile_install_priority: bool,
+    /// `nix profile list --json` (Nix 2.17).
+    ///
+    /// Otherwise, the profile's `manifest.json` is read directly.
+    pub profile_list_json: bool,
+    /// `nix flake update --flake FLAKE [INPUT...]` updates individual inputs (Nix 2.19).
+    ///
+    /// Otherwise, `nix flake update FLAKE` updates all inputs, and individual inputs are updated
+    /// with `nix flake lock --update-input INPUT FLAKE`.
+    pub flake_update_inputs: bool,
+    /// `nix profile install` is spelled `nix profile add` (Nix 2.30). The old name is still
+    /// accepted as an alias.
+    pub profile_add: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_version_parse() {
+        assert_eq!(
+            "2.24.10".parse::<Version>().unwrap(),
+            Version::new(2, 24, 10)
+        );
+        assert_eq!("2.18".parse::<Version>().unwrap(), Version::new(2, 18, 0));
+        assert_eq!(
+            "2.25.0pre20241101_3b3e2ee".parse::<Version>().unwrap(),
+            Version::new(2, 25, 0)
+        );
+        assert_eq!(
+            "2.92.0-dev".parse::<Version>().unwrap(),
+            Version::new(2, 92, 0)
+        );
+        assert!("2".parse::<Version>().is_err());
+        assert!("nix".parse::<Version>().is_err());
+    }
+
+    #[test]
+    fn test_nix_version_parse() {
+        assert_eq!(
+            NixVersion::parse("nix (Nix) 2.24.10\n").unwrap(),
+            NixVersion {
+                implementation: NixImplementation::Nix,
+                version: Version::new(2, 24, 10),
+            }
+        );
+        assert_eq!(
+            NixVersion::parse("nix (Lix, like Nix) 2.91.1").unwrap(),
+            NixVersion {
+                implementation: NixImplementation::Lix,
+                version: Version::new(2, 91, 1),
+            }
+        );
+        assert_eq!(
+            NixVersion::parse("nix (Determinate Nix 3.6.2) 2.29.0").unwrap(),
+            NixVersion {
+                implementation: NixImplementation::Determinate,
+                version: Version::new(2, 29, 0),
+            }
+        );
+        assert!(NixVersion::parse("").is_err());
+    }
+
+    #[test]
+    fn test_check_supported() {
+        let check = |output| NixVersion::parse(output).unwrap().check_supported();
+        assert!(check("nix (Nix) 2.3.16").is_err());
+        assert!(check("nix (Nix) 2.4").is_ok());
+        assert!(check("nix (Nix) 2.16.2").is_ok());
+        assert!(check("nix (Nix) 2.24.10").is_ok());
+        assert!(check("nix (Lix, like Nix) 2.90.0").is_ok());
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let capabilities = |output| NixVersion::parse(output).unwrap().capabilities();
+        assert_eq!(
+            capabilities("nix (Nix) 2.4"),
+            NixCapabilities {
+                build_print_out_paths: false,
+                profile_install_priority: false,
+                profile_list_json: false,
+                flake_update_inputs: false,
+                profile_add: false,
+            }
+        );
+        assert_eq!(
+            capabilities("nix (Nix) 2.8.1"),
+            NixCapabilities {
+                build_print_out_paths: true,
+                profile_install_priority: false,
+                profile_list_json: false,
+                flake_update_inputs: false,
+                profile_add: false,
+            }
+        );
+        assert_eq!(
+            capabilities("nix (Nix) 2.16.2"),
+            NixCapabilities {
+                build_print_out_paths: true,
+                profile_install_priority: true,
+                profile_list_json: false,
+                flake_update_inputs: false,
+                profile_add: false,
+            }
+        );
+        assert_eq!(
+            capabilities("nix (Nix) 2.18.1"),
+            NixCapabilities {
+                build_print_out_paths: true,
+                profile_install_priority: true,
+                profile_list_json: true,
+                flake_update_inputs: false,
+                profile_add: false,
+            }
+        );
+        assert_eq!(
+            capabilities("nix (Nix) 2.24.10"),
+            NixCapabilities {
+                build_print_out_paths: true,
+                profile_install_priority: true,
+                profile_list_json: true,
+                flake_update_inputs: true,
+                profile_add: false,
+            }
+        );
+        assert_eq!(
+            capabilities("nix (Determinate Nix 3.8.0) 2.30.0"),
+            NixCapabilities {
+                build_print_out_paths: true,
+                profile_install_priority: true,
+                profile_list_json: true,
+                flake_update_inputs: true,
+                profile_add: true,
+            }
+        );
+        assert_eq!(
+            capabilities("nix (Lix, like Nix) 2.91.1"),
+            NixCapabilities {
+                build_print_out_paths: true,
+                profile_install_priority: true,
+                profile_list_json: true,
+                flake_update_inputs: false,
+                profile_add: false,
+            }
+        );
+    }
+}
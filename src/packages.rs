@@ -1,45 +1,211 @@
 use std::collections::BTreeSet;
+use std::fmt::Display;
 
 use camino::Utf8Path;
 use camino::Utf8PathBuf;
-use command_error::CommandExt;
-use miette::Context;
-use miette::IntoDiagnostic;
+use miette::miette;
 
+use crate::config::ProfileMode;
+use crate::confirm::confirm;
+use crate::conflicts::check_conflicts;
+use crate::conflicts::check_shadowed;
 use crate::flake::Flake;
+use crate::flake::PathFlake;
+use crate::flake_lock::InputChange;
 use crate::format_bulleted_list;
+use crate::gc::GcRoots;
+use crate::git::GitCheckout;
+use crate::history::FlakeProvenance;
+use crate::nix::report_dirty;
 use crate::nix::Nix;
 use crate::nix::ProfileList;
+use crate::nix::ProfileListV3Element;
 use crate::nix::ResolvedFlake;
+use crate::nix::DEFAULT_PRIORITY;
+use crate::status::format_elements;
+use crate::update::UpdateOptions;
 
+/// A summary of the changes made by [`ensure_packages`].
+#[derive(serde::Serialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct PackagesReport {
+    /// Where the configuration came from.
+    pub flake: Option<FlakeProvenance>,
+    /// Inputs changed by `nix flake update`, if `--update` was given.
+    pub input_changes: Option<Vec<InputChange>>,
+    /// The store paths built for the configuration.
+    pub out_paths: BTreeSet<Utf8PathBuf>,
+    /// Store paths removed from the profile.
+    pub removed_paths: BTreeSet<Utf8PathBuf>,
+    /// Store paths added to the profile.
+    pub added_paths: BTreeSet<Utf8PathBuf>,
+}
+
+impl PackagesReport {
+    /// Did the profile change?
+    pub fn changed(&self) -> bool {
+        !self.removed_paths.is_empty() || !self.added_paths.is_empty()
+    }
+}
+
+/// Options for [`ensure_packages`].
+#[derive(Debug, Clone, Default)]
+pub struct PackagesOptions {
+    /// If given, the flake's inputs are updated before building.
+    pub update: Option<UpdateOptions>,
+    /// Refuse to switch from a dirty Git tree.
+    pub require_clean: bool,
+    /// Whether to remove packages not managed by home-mangler.
+    pub profile_mode: ProfileMode,
+    /// Don't ask for confirmation before removing packages not managed by home-mangler.
+    pub assume_yes: bool,
+    /// Priority to install the packages with.
+    pub priority: u16,
+    /// Fail when several packages provide the same file in `bin/`.
+    pub deny_bin_conflicts: bool,
+    /// Register built packages as garbage collector roots.
+    pub gc_roots: Option<GcRoots>,
+}
+
+/// Build and install the packages for `hostname`.
 pub fn ensure_packages(
     nix: &Nix,
     flake: &Flake,
     hostname: &str,
-    update: bool,
-) -> miette::Result<()> {
-    if update {
-        nix.flake_update(flake)
-            .wrap_err_with(|| format!("Failed to update `flake.lock` for {flake}"))?;
+    options: &PackagesOptions,
+) -> miette::Result<PackagesReport> {
+    let mut report = PackagesReport::default();
+
+    if options.priority != DEFAULT_PRIORITY && !nix.capabilities().profile_install_priority {
+        return Err(miette!(
+            help = "Upgrade to Nix 2.14 or newer, or unset `priority`",
+            "{} can't install packages with a priority",
+            nix.version()
+        ));
+    }
+
+    // Check before updating, so that `flake.lock` isn't changed or committed when we refuse to
+    // switch. Otherwise, wait for the flake metadata we need anyway.
+    let checked_clean = match flake {
+        Flake::Path(path_flake) => {
+            check_worktree_clean(path_flake, options.require_clean)?;
+            true
+        }
+        Flake::Url(_) if options.update.is_some() => {
+            nix.flake_metadata(flake)?
+                .check_clean(options.require_clean)?;
+            true
+        }
+        Flake::Url(_) => false,
+    };
+
+    if let Some(update) = &options.update {
+        report.input_changes = Some(crate::update::update_flake(nix, flake, update)?);
     }
 
-    let flake_attr = format!("home-mangler.{hostname}.packages");
+    let flake_attr = flake_attr(hostname);
     let package_installable = format!("{flake}#{flake_attr}");
 
     // TODO: We have a few things we could run in separate threads here.
     let resolved = nix.resolve(flake.clone())?;
+    if !checked_clean {
+        resolved.metadata.check_clean(options.require_clean)?;
+    }
+    report.flake = Some(FlakeProvenance::from(&resolved.metadata));
 
     tracing::info!("Building packages for install");
-    let package_out_paths = nix.build(&package_installable)?;
+    let out_link = match &options.gc_roots {
+        Some(gc_roots) => Some(gc_roots.out_link(PACKAGES_NAME)?),
+        None => None,
+    };
+    let package_out_paths = nix.build(&package_installable, out_link.as_deref())?;
+    report.out_paths = package_out_paths.clone();
     let profile = nix.profile_list()?;
     let missing_paths = profile.missing_paths(&package_out_paths)?;
     if !missing_paths.is_empty() {
-        let removed_paths = profile.remove_old_packages(nix, &resolved, &flake_attr)?;
-        tracing::info!(
-            "Installing new packages to `nix profile`:\n{}",
-            format_bulleted_list(&missing_paths)
+        check_package_set(nix, &package_installable, options.deny_bin_conflicts)?;
+    }
+    let unmanaged = match options.profile_mode {
+        ProfileMode::Additive => Vec::new(),
+        ProfileMode::Strict => profile.unmanaged_elements(&resolved, &flake_attr),
+    };
+
+    if !missing_paths.is_empty() || !unmanaged.is_empty() {
+        if !unmanaged.is_empty() {
+            tracing::info!(
+                "Removing packages not managed by home-mangler (`profile-mode = \"strict\"`):\n{}",
+                format_elements(&unmanaged)
+            );
+            if !options.assume_yes && !confirm("Remove these packages from `nix profile`?")? {
+                return Err(miette!(
+                    help = "Remove them with `home-mangler adopt --remove`, or set `profile-mode = \"additive\"`",
+                    "Refusing to switch with packages not managed by home-mangler in the profile"
+                ));
+            }
+        }
+
+        let managed = profile
+            .elements()
+            .into_iter()
+            .filter_map(|(name, element)| {
+                let managed_match = element.managed_match(&resolved, &flake_attr)?;
+                match managed_match {
+                    ManagedMatch::Flake => {
+                        tracing::debug!(name, "Found home-mangler packages: {managed_match}");
+                    }
+                    _ => {
+                        tracing::info!(
+                            "Recognized `nix profile` element {name} as home-mangler packages: {managed_match}"
+                        );
+                    }
+                }
+                Some((name, element, managed_match))
+            })
+            .collect::<Vec<_>>();
+        // `nix profile upgrade` re-evaluates the element's original URL, so it only works when
+        // that's the current flake. It also keeps the element's priority.
+        let upgrade = matches!(
+            managed.as_slice(),
+            [(_, element, ManagedMatch::Flake)] if element.priority == options.priority
         );
-        install_new_packages(nix, &package_installable)?;
+        let managed = managed
+            .into_iter()
+            .map(|(name, element, _)| (name, element))
+            .collect();
+
+        if !missing_paths.is_empty() {
+            // In strict mode, every other element is removed.
+            let others = match options.profile_mode {
+                ProfileMode::Additive => profile.unmanaged_elements(&resolved, &flake_attr),
+                ProfileMode::Strict => Vec::new(),
+            };
+            check_conflicts(&package_out_paths, options.priority, &others)?;
+        }
+
+        // Record the current generation to restore if the new one doesn't contain the new
+        // packages. Don't start if we can't.
+        let generation = nix.profile_generation();
+        if generation.is_none() && !profile.elements().is_empty() {
+            return Err(miette!(
+                help = "Use `--profile` to select your Nix profile",
+                "Could not find the current generation of the Nix profile, so it couldn't be \
+                 restored if switching fails"
+            ));
+        }
+        let removed_paths = update_profile(
+            nix,
+            &package_installable,
+            options.priority,
+            &missing_paths,
+            managed,
+            unmanaged,
+            upgrade,
+        )
+        .and_then(|removed_paths| {
+            check_installed(nix, &package_out_paths)?;
+            Ok(removed_paths)
+        })
+        .map_err(|err| restore_generation(nix, generation, err))?;
 
         let removed_paths = removed_paths.difference(&missing_paths).copied().collect();
         let added_paths = missing_paths;
@@ -47,16 +213,53 @@ pub fn ensure_packages(
         let diff = crate::diff_trees::diff_trees(&removed_paths, &added_paths)?;
 
         tracing::info!("Updated `nix profile`:\n{diff}");
+
+        report.removed_paths = removed_paths.iter().map(|p| p.to_path_buf()).collect();
+        report.added_paths = added_paths.iter().map(|p| p.to_path_buf()).collect();
     } else {
         tracing::info!(
             "Already up to date:\n{}",
             format_bulleted_list(&package_out_paths)
         );
     }
-    Ok(())
+
+    // Only prune after installing, so that a failed switch keeps the previous build's roots.
+    if let Some(gc_roots) = &options.gc_roots {
+        gc_roots.prune();
+    }
+    Ok(report)
 }
 
 impl ProfileList {
+    /// The profile's elements, with the names `nix profile remove` uses to refer to them.
+    ///
+    /// For versions 1-2, elements are referred to by their index.
+    pub fn elements(&self) -> Vec<(String, &ProfileListV3Element)> {
+        match &self {
+            ProfileList::V2(packages) => packages
+                .iter()
+                .enumerate()
+                .map(|(i, package)| (i.to_string(), package))
+                .collect(),
+            ProfileList::V3(packages) => packages
+                .iter()
+                .map(|(name, package)| (name.clone(), package))
+                .collect(),
+        }
+    }
+
+    /// Elements not installed by home-mangler.
+    pub fn unmanaged_elements(
+        &self,
+        flake: &ResolvedFlake,
+        attr_path: &str,
+    ) -> Vec<(String, &ProfileListV3Element)> {
+        self.elements()
+            .into_iter()
+            .filter(|(_, package)| !package.is_managed(flake, attr_path))
+            .collect()
+    }
+
     /// Find store paths that aren't installed in the profile.
     pub fn missing_paths<'p>(
         &self,
@@ -65,79 +268,447 @@ impl ProfileList {
         let mut uninstalled_paths: BTreeSet<&Utf8Path> =
             out_paths.iter().map(|p| p.as_path()).collect();
 
-        match &self {
-            ProfileList::V2(packages) => {
-                for package in packages {
-                    for store_path in &package.store_paths {
-                        uninstalled_paths.remove(store_path.as_path());
-                    }
-                }
-            }
-            ProfileList::V3(packages) => {
-                for package in packages.values() {
-                    for store_path in &package.store_paths {
-                        uninstalled_paths.remove(store_path.as_path());
-                    }
-                }
+        for (_, package) in self.elements() {
+            for store_path in &package.store_paths {
+                uninstalled_paths.remove(store_path.as_path());
             }
         }
 
         Ok(uninstalled_paths)
     }
+}
 
-    pub fn remove_old_packages(
-        &self,
-        nix: &Nix,
-        flake: &ResolvedFlake,
-        attr_path: &str,
-    ) -> miette::Result<BTreeSet<&Utf8Path>> {
-        let mut elements_to_remove = vec![];
-        let mut paths_to_remove = BTreeSet::new();
-        match &self {
-            ProfileList::V2(packages) => {
-                for (i, package) in packages.iter().enumerate() {
-                    if package.attr_path.as_deref() == Some(attr_path)
-                        && package.original_url.as_deref()
-                            == Some(flake.metadata.original_url.as_str())
-                    {
-                        elements_to_remove.push(i.to_string());
-                        paths_to_remove.extend(package.store_paths.iter().map(|p| p.as_path()));
-                    }
-                }
+/// The name of the derivation built by `makePackages`.
+const PACKAGES_NAME: &str = "home-mangler-packages";
+
+/// Why a profile element was recognized as home-mangler packages.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ManagedMatch {
+    /// Installed from the current flake's `home-mangler.${hostname}.packages`.
+    Flake,
+    /// Installed from `home-mangler.${hostname}.packages` in a flake at a different URL, like
+    /// after moving the configuration repository or setting `use-path-flake`.
+    AttrPath { original_url: Option<String> },
+    /// Its store path is a `home-mangler-packages` derivation, like packages for another
+    /// hostname.
+    StorePathName(Utf8PathBuf),
+}
+
+impl Display for ManagedMatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ManagedMatch::Flake => write!(f, "installed from this flake"),
+            ManagedMatch::AttrPath {
+                original_url: Some(original_url),
+            } => write!(f, "same attribute, installed from {original_url}"),
+            ManagedMatch::AttrPath { original_url: None } => {
+                write!(f, "same attribute, installed from an unknown flake")
             }
-            ProfileList::V3(packages) => {
-                for (name, package) in packages {
-                    if package.attr_path.as_deref() == Some(attr_path)
-                        && package.original_url.as_deref()
-                            == Some(flake.metadata.original_url.as_str())
-                    {
-                        elements_to_remove.push(name.clone());
-                        paths_to_remove.extend(package.store_paths.iter().map(|p| p.as_path()));
-                    }
-                }
+            ManagedMatch::StorePathName(path) => {
+                write!(f, "store path {path} is named `{PACKAGES_NAME}`")
             }
         }
+    }
+}
 
-        if !elements_to_remove.is_empty() {
-            // TODO: Confirm before removing.
-            tracing::info!(
-                "Removing old packages from `nix profile`:\n{}",
-                format_bulleted_list(&paths_to_remove)
+impl ProfileListV3Element {
+    /// Was this element installed by home-mangler from the given flake?
+    pub fn is_managed(&self, flake: &ResolvedFlake, attr_path: &str) -> bool {
+        self.managed_match(flake, attr_path).is_some()
+    }
+
+    /// Why this element is recognized as home-mangler packages, if it is.
+    ///
+    /// Elements are matched on their attribute path rather than the whole flake URL, so they're
+    /// still recognized after the flake moves; otherwise a second, conflicting copy of the
+    /// packages would be installed.
+    pub fn managed_match(&self, flake: &ResolvedFlake, attr_path: &str) -> Option<ManagedMatch> {
+        if self.attr_path.as_deref() == Some(attr_path) {
+            return Some(
+                if self.original_url.as_deref() == Some(flake.metadata.original_url.as_str()) {
+                    ManagedMatch::Flake
+                } else {
+                    ManagedMatch::AttrPath {
+                        original_url: self.original_url.clone(),
+                    }
+                },
             );
-            nix.command(&["profile", "remove"])
-                .args(elements_to_remove.iter().map(|element| element.to_string()))
-                .status_checked()
-                .into_diagnostic()?;
         }
 
-        Ok(paths_to_remove)
+        self.store_paths
+            .iter()
+            .find(|path| {
+                path.file_name()
+                    .and_then(|name| name.split_once('-'))
+                    .is_some_and(|(_hash, name)| name == PACKAGES_NAME)
+            })
+            .map(|path| ManagedMatch::StorePathName(path.clone()))
+    }
+
+    /// Where the element was installed from, like `flake:nixpkgs#legacyPackages.x86_64-linux.hello`,
+    /// or its store paths if it wasn't installed from a flake.
+    pub fn source(&self) -> String {
+        match (
+            self.original_url.as_deref().or(self.url.as_deref()),
+            &self.attr_path,
+        ) {
+            (Some(url), Some(attr_path)) => format!("{url}#{attr_path}"),
+            _ => self
+                .store_paths
+                .iter()
+                .map(|path| path.as_str())
+                .collect::<Vec<_>>()
+                .join(" "),
+        }
     }
 }
 
-fn install_new_packages(nix: &Nix, flake_ref: &str) -> miette::Result<()> {
-    nix.command(&["profile", "install"])
-        .args(["--print-build-logs", flake_ref])
-        .status_checked()
-        .into_diagnostic()
+impl Nix {
+    /// Remove elements from the profile, by name or index.
+    pub fn remove_elements(&self, elements: &[String]) -> miette::Result<()> {
+        self.run(self.command(&["profile", "remove"]).args(elements))
+            .map(|_| ())
+    }
+
+    /// Upgrade an element in the profile, by name or index.
+    pub fn upgrade_element(&self, element: &str) -> miette::Result<()> {
+        self.run(
+            self.command(&["profile", "upgrade"])
+                .args(["--print-build-logs", element]),
+        )
         .map(|_| ())
+    }
+}
+
+/// Install the new packages and remove `unmanaged` elements from the profile, returning the
+/// store paths removed from the profile.
+///
+/// The profile moves to the new packages in a single generation. When only the home-mangler
+/// packages change and `upgrade` is set, they're upgraded in place with `nix profile upgrade`.
+/// Otherwise, when elements need to be removed before the new packages are installed, so they
+/// don't conflict, both steps happen in a copy of the profile which then replaces it (see
+/// [`Nix::stage_profile`]).
+#[tracing::instrument(level = "debug", skip_all)]
+fn update_profile<'a>(
+    nix: &Nix,
+    installable: &str,
+    priority: u16,
+    missing_paths: &BTreeSet<&Utf8Path>,
+    managed: Vec<(String, &'a ProfileListV3Element)>,
+    unmanaged: Vec<(String, &'a ProfileListV3Element)>,
+    upgrade: bool,
+) -> miette::Result<BTreeSet<&'a Utf8Path>> {
+    if let [(name, element)] = managed.as_slice() {
+        if upgrade && !missing_paths.is_empty() && unmanaged.is_empty() {
+            tracing::info!(
+                "Upgrading packages in `nix profile`:\n{}",
+                format_bulleted_list(missing_paths)
+            );
+            nix.upgrade_element(name)?;
+            return Ok(element.store_paths.iter().map(|p| p.as_path()).collect());
+        }
+    }
+
+    let mut elements_to_remove = unmanaged;
+    if !missing_paths.is_empty() {
+        // We're replacing the home-mangler packages, so remove the old ones.
+        // TODO: Confirm before removing.
+        elements_to_remove.extend(managed);
+    }
+
+    if elements_to_remove.is_empty() || missing_paths.is_empty() {
+        // A single command, so a single generation.
+        let removed_paths = remove_old_packages(nix, &elements_to_remove)?;
+        if !missing_paths.is_empty() {
+            install_new_packages(nix, installable, priority, missing_paths)?;
+        }
+        return Ok(removed_paths);
+    }
+
+    let staged = nix.stage_profile()?;
+    let removed_paths = remove_old_packages(staged.nix(), &elements_to_remove)?;
+    install_new_packages(staged.nix(), installable, priority, missing_paths)?;
+    staged.commit(nix)?;
+    Ok(removed_paths)
+}
+
+/// Check if a local flake's Git working tree is dirty.
+///
+/// We ask Git directly, because `path:` flakes never report a `dirtyRevision`.
+fn check_worktree_clean(flake: &PathFlake, require_clean: bool) -> miette::Result<()> {
+    let Some(git) = GitCheckout::find(flake.path())? else {
+        return Ok(());
+    };
+    if git.is_worktree_dirty(flake.use_path_flake())? {
+        // Like the `dirtyRevision` Nix reports for Git flakes.
+        let description = match git.head_revision()? {
+            Some(revision) => format!("{} ({revision}-dirty)", flake.path()),
+            None => flake.path().to_string(),
+        };
+        report_dirty(&description, require_clean)?;
+    }
+    Ok(())
+}
+
+/// Check the inputs of the package set for shadowed files.
+///
+/// This is advisory: it only fails if `deny_bin` is set and files in `bin/` are shadowed.
+fn check_package_set(nix: &Nix, installable: &str, deny_bin: bool) -> miette::Result<()> {
+    // `symlinkJoin` keeps its inputs in the `paths` attribute. Package sets not built with
+    // `makePackages` may not have it.
+    let inputs = match nix.eval_json::<Vec<Utf8PathBuf>>(&format!("{installable}.paths")) {
+        Ok(inputs) => inputs,
+        Err(err) => {
+            tracing::debug!("Not checking package set for shadowed files: {err:?}");
+            return Ok(());
+        }
+    };
+    check_shadowed(&inputs, deny_bin)
+}
+
+/// Check that the profile contains `out_paths` after [`update_profile`].
+///
+/// `nix profile upgrade` re-evaluates the flake, so it may not install exactly what we built.
+fn check_installed(nix: &Nix, out_paths: &BTreeSet<Utf8PathBuf>) -> miette::Result<()> {
+    let missing_paths = nix.profile_list()?.missing_paths(out_paths)?;
+    if !missing_paths.is_empty() {
+        return Err(miette!(
+            "`nix profile` doesn't contain the new packages:\n{}",
+            format_bulleted_list(&missing_paths)
+        ));
+    }
+    Ok(())
+}
+
+/// Put the profile back to `generation` after [`update_profile`] fails.
+///
+/// `generation` is only `None` if the profile was empty, so there's nothing to restore.
+fn restore_generation(nix: &Nix, generation: Option<u64>, err: miette::Report) -> miette::Report {
+    let Some(generation) = generation else {
+        return err.wrap_err("Failed to update `nix profile`");
+    };
+    match nix.rollback_to(generation) {
+        Ok(()) => {
+            tracing::warn!("Restored `nix profile` generation {generation}");
+            err.wrap_err("Failed to update `nix profile`")
+        }
+        Err(rollback_err) => {
+            tracing::error!("{rollback_err:?}");
+            err.wrap_err(format!(
+                "Failed to update `nix profile`, and failed to restore generation {generation}"
+            ))
+        }
+    }
+}
+
+/// Remove the given elements from the profile and return their store paths.
+#[tracing::instrument(level = "debug", skip_all)]
+fn remove_old_packages<'a>(
+    nix: &Nix,
+    elements: &[(String, &'a ProfileListV3Element)],
+) -> miette::Result<BTreeSet<&'a Utf8Path>> {
+    let paths_to_remove = elements
+        .iter()
+        .flat_map(|(_, element)| element.store_paths.iter().map(|p| p.as_path()))
+        .collect::<BTreeSet<_>>();
+
+    if !elements.is_empty() {
+        tracing::info!(
+            "Removing old packages from `nix profile`:\n{}",
+            format_bulleted_list(&paths_to_remove)
+        );
+        // Remove all the elements at once; for versions 1-2, elements are referred to by
+        // their indexes, which change when elements are removed.
+        let names = elements
+            .iter()
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        nix.remove_elements(&names)?;
+    }
+
+    Ok(paths_to_remove)
+}
+
+/// The flake attribute containing the packages for `hostname`.
+pub fn flake_attr(hostname: &str) -> String {
+    format!("home-mangler.{hostname}.packages")
+}
+
+#[tracing::instrument(level = "debug", skip(nix, missing_paths))]
+fn install_new_packages(
+    nix: &Nix,
+    flake_ref: &str,
+    priority: u16,
+    missing_paths: &BTreeSet<&Utf8Path>,
+) -> miette::Result<()> {
+    tracing::info!(
+        "Installing new packages to `nix profile`:\n{}",
+        format_bulleted_list(missing_paths)
+    );
+    let subcommand = if nix.capabilities().profile_add {
+        "add"
+    } else {
+        "install"
+    };
+    let mut command = nix.command(&["profile", subcommand]);
+    command.arg("--print-build-logs");
+    if priority != DEFAULT_PRIORITY {
+        command.args(["--priority", &priority.to_string()]);
+    }
+    nix.run(command.arg(flake_ref)).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    const ATTR_PATH: &str = "home-mangler.box.packages";
+
+    fn resolved(original_url: &str) -> ResolvedFlake {
+        ResolvedFlake {
+            original: Flake::Url(original_url.to_owned()),
+            metadata: serde_json::from_value(serde_json::json!({
+                "path": "/nix/store/00000000000000000000000000000000-source",
+                "original": {},
+                "originalUrl": original_url,
+                "resolved": {},
+                "resolvedUrl": original_url,
+            }))
+            .unwrap(),
+        }
+    }
+
+    fn profile_element(
+        original_url: Option<&str>,
+        attr_path: Option<&str>,
+        store_path: &str,
+    ) -> ProfileListV3Element {
+        serde_json::from_value(serde_json::json!({
+            "storePaths": [store_path],
+            "originalUrl": original_url,
+            "attrPath": attr_path,
+        }))
+        .unwrap()
+    }
+
+    const PACKAGES: &str = "/nix/store/dccm0y9xpz85sm9gsfb0n7rs07cp4l7p-home-mangler-packages";
+
+    #[test]
+    fn test_managed_match_same_flake() {
+        let flake = resolved("git+file:///home/user/dotfiles");
+        let element = profile_element(
+            Some("git+file:///home/user/dotfiles"),
+            Some(ATTR_PATH),
+            PACKAGES,
+        );
+        assert_eq!(
+            element.managed_match(&flake, ATTR_PATH),
+            Some(ManagedMatch::Flake)
+        );
+    }
+
+    #[test]
+    fn test_managed_match_moved_flake() {
+        let flake = resolved("git+file:///home/user/config");
+        let element = profile_element(
+            Some("git+file:///home/user/dotfiles"),
+            Some(ATTR_PATH),
+            PACKAGES,
+        );
+        assert_eq!(
+            element.managed_match(&flake, ATTR_PATH),
+            Some(ManagedMatch::AttrPath {
+                original_url: Some("git+file:///home/user/dotfiles".to_owned())
+            })
+        );
+    }
+
+    #[test]
+    fn test_managed_match_use_path_flake() {
+        // Setting `use-path-flake` switches from `git+file:` to `path:` URLs.
+        let flake = resolved("path:/home/user/dotfiles");
+        let element = profile_element(
+            Some("git+file:///home/user/dotfiles"),
+            Some(ATTR_PATH),
+            PACKAGES,
+        );
+        assert_eq!(
+            element.managed_match(&flake, ATTR_PATH),
+            Some(ManagedMatch::AttrPath {
+                original_url: Some("git+file:///home/user/dotfiles".to_owned())
+            })
+        );
+    }
+
+    #[test]
+    fn test_managed_match_store_path_name() {
+        let flake = resolved("git+file:///home/user/dotfiles");
+        // Packages for another hostname.
+        let element = profile_element(
+            Some("git+file:///home/user/dotfiles"),
+            Some("home-mangler.other.packages"),
+            PACKAGES,
+        );
+        assert_eq!(
+            element.managed_match(&flake, ATTR_PATH),
+            Some(ManagedMatch::StorePathName(PACKAGES.into()))
+        );
+
+        // Installed from a store path.
+        let element = profile_element(None, None, PACKAGES);
+        assert_eq!(
+            element.managed_match(&flake, ATTR_PATH),
+            Some(ManagedMatch::StorePathName(PACKAGES.into()))
+        );
+    }
+
+    #[test]
+    fn test_managed_match_unmanaged() {
+        let flake = resolved("git+file:///home/user/dotfiles");
+        let element = profile_element(
+            Some("flake:nixpkgs"),
+            Some("legacyPackages.x86_64-linux.hello"),
+            "/nix/store/63l345l7dgcfz789w1y93j1540czafqh-hello-2.12.1",
+        );
+        assert_eq!(element.managed_match(&flake, ATTR_PATH), None);
+
+        // Only the whole derivation name counts.
+        let element = profile_element(
+            None,
+            None,
+            "/nix/store/63l345l7dgcfz789w1y93j1540czafqh-home-mangler-packages-extra",
+        );
+        assert_eq!(element.managed_match(&flake, ATTR_PATH), None);
+    }
+
+    #[test]
+    fn test_unmanaged_elements() {
+        let flake = resolved("path:/home/user/dotfiles");
+        let profile = ProfileList::V3(BTreeMap::from([
+            (
+                "home-mangler-packages".to_owned(),
+                profile_element(
+                    Some("git+file:///home/user/dotfiles"),
+                    Some(ATTR_PATH),
+                    PACKAGES,
+                ),
+            ),
+            (
+                "hello".to_owned(),
+                profile_element(
+                    Some("flake:nixpkgs"),
+                    Some("legacyPackages.x86_64-linux.hello"),
+                    "/nix/store/63l345l7dgcfz789w1y93j1540czafqh-hello-2.12.1",
+                ),
+            ),
+        ]));
+        let unmanaged = profile
+            .unmanaged_elements(&flake, ATTR_PATH)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        assert_eq!(unmanaged, vec!["hello".to_owned()]);
+    }
 }
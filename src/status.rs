@@ -0,0 +1,66 @@
+use crate::cli::StatusArgs;
+use crate::config::Config;
+use crate::format_bulleted_list;
+use crate::nix::ProfileListV3Element;
+use crate::packages::flake_attr;
+
+/// Show which packages in the profile are managed by home-mangler.
+pub fn status(config: &Config, args: &StatusArgs) -> miette::Result<()> {
+    let nix = config.nix()?;
+    let flake = nix.resolve(config.flake()?)?;
+    let attr_path = flake_attr(config.hostname());
+    let profile = nix.profile_list()?;
+
+    let (managed, unmanaged): (Vec<_>, Vec<_>) = profile
+        .elements()
+        .into_iter()
+        .partition(|(_, element)| element.is_managed(&flake, &attr_path));
+
+    if args.unmanaged {
+        if unmanaged.is_empty() {
+            tracing::info!("All packages in the profile are managed by home-mangler");
+        } else {
+            tracing::info!(
+                "Packages not managed by home-mangler:\n{}",
+                format_elements(&unmanaged)
+            );
+        }
+        return Ok(());
+    }
+
+    if managed.is_empty() {
+        tracing::info!("No home-mangler packages are installed for {attr_path}");
+    } else {
+        tracing::info!(
+            "Managed by home-mangler:\n{}",
+            format_bulleted_list(managed.iter().map(|(name, element)| {
+                let reason = element
+                    .managed_match(&flake, &attr_path)
+                    .map(|managed_match| managed_match.to_string())
+                    .unwrap_or_default();
+                format!("{name}: {} ({reason})", element.source())
+            }))
+        );
+    }
+
+    match unmanaged.len() {
+        0 => {}
+        1 => tracing::info!(
+            "1 package is not managed by home-mangler; run `home-mangler status --unmanaged` to list it"
+        ),
+        count => tracing::info!(
+            "{count} packages are not managed by home-mangler; run `home-mangler status --unmanaged` to list them"
+        ),
+    }
+
+    Ok(())
+}
+
+/// Format profile elements as a bulleted list of names and sources.
+pub fn format_elements(elements: &[(String, &ProfileListV3Element)]) -> String {
+    format_bulleted_list(
+        elements
+            .iter()
+            .map(|(name, element)| format!("{name}: {}", element.source())),
+    )
+}
@@ -0,0 +1,53 @@
+use miette::IntoDiagnostic;
+
+use crate::config::Config;
+use crate::config::Step;
+use crate::gc;
+use crate::history;
+use crate::nix::Nix;
+use crate::packages;
+
+/// Build and install the packages, then collect garbage if configured to.
+pub fn switch(config: &Config) -> miette::Result<()> {
+    let nix = config.nix()?;
+    let changed = switch_with_nix(config, &nix)?;
+
+    if changed && config.gc_after_switch() {
+        // The switch itself succeeded, so don't fail because of this.
+        if let Err(err) = gc::collect_garbage(&nix, &config.gc_options(None)) {
+            tracing::warn!("Failed to collect garbage after switching: {err:?}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Switch to the new configuration and return whether the profile changed.
+pub fn switch_with_nix(config: &Config, nix: &Nix) -> miette::Result<bool> {
+    let flake = config.flake()?;
+    let hostname = config.hostname();
+    tracing::debug!(%flake, %hostname, "Resolved configuration");
+
+    if !config.steps().contains(&Step::Packages) {
+        tracing::info!("Skipping packages step");
+        return Ok(false);
+    }
+
+    let options = config.packages_options()?;
+    let report = tracing::debug_span!("step", step = "packages")
+        .in_scope(|| packages::ensure_packages(nix, &flake, hostname, &options))?;
+
+    history::append(
+        &config.history_path()?,
+        &history::HistoryEntry::new(hostname, &report)?,
+    )?;
+
+    if config.json() {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&report).into_diagnostic()?
+        );
+    }
+
+    Ok(report.changed())
+}
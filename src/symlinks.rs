@@ -0,0 +1,103 @@
+use camino::Utf8Path;
+use camino::Utf8PathBuf;
+use miette::miette;
+use miette::Context;
+use miette::IntoDiagnostic;
+
+/// Longer chains of symlinks are assumed to be cycles, like Linux's limit for resolving paths.
+const MAX_SYMLINK_HOPS: usize = 40;
+
+/// Follow the chain of symlinks starting at `path`, yielding each symlink and the path it
+/// points to. Relative targets are resolved from the symlink's directory.
+///
+/// The chain ends with an error at the first path which isn't a symlink or can't be read, or
+/// after too many symlinks.
+pub fn symlink_chain(
+    path: &Utf8Path,
+) -> impl Iterator<Item = miette::Result<(Utf8PathBuf, Utf8PathBuf)>> {
+    let mut path = Some(path.to_owned());
+    let mut hops = 0;
+    std::iter::from_fn(move || {
+        let link = path.take()?;
+        if hops == MAX_SYMLINK_HOPS {
+            return Some(Err(miette!("Too many levels of symlinks at {link}")));
+        }
+        hops += 1;
+
+        let target = match link
+            .read_link_utf8()
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to read symlink {link}"))
+        {
+            Ok(target) => target,
+            Err(err) => return Some(Err(err)),
+        };
+        let target = match link.parent() {
+            Some(parent) if target.is_relative() => parent.join(target),
+            _ => target,
+        };
+        path = Some(target.clone());
+        Some(Ok((link, target)))
+    })
+}
+
+/// Follow the symlinks starting at `path` to the first path which isn't a symlink or can't be
+/// read, so dangling symlinks resolve to their target.
+pub fn link_target(path: &Utf8Path) -> Utf8PathBuf {
+    symlink_chain(path)
+        .map_while(Result::ok)
+        .last()
+        .map(|(_, target)| target)
+        .unwrap_or_else(|| path.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+
+    #[test]
+    fn test_link_target() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+        std::fs::write(dir.join("file"), "").unwrap();
+        symlink("file", dir.join("a")).unwrap();
+        symlink(dir.join("a"), dir.join("b")).unwrap();
+        symlink("missing", dir.join("dangling")).unwrap();
+        symlink("cycle-b", dir.join("cycle-a")).unwrap();
+        symlink("cycle-a", dir.join("cycle-b")).unwrap();
+
+        assert_eq!(link_target(&dir.join("file")), dir.join("file"));
+        assert_eq!(link_target(&dir.join("b")), dir.join("file"));
+        assert_eq!(link_target(&dir.join("dangling")), dir.join("missing"));
+        // Stops after too many symlinks.
+        link_target(&dir.join("cycle-a"));
+    }
+
+    #[test]
+    fn test_symlink_chain() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+        std::fs::create_dir(dir.join("profile-7-link")).unwrap();
+        symlink("profile-7-link", dir.join("profile")).unwrap();
+        symlink(dir.join("profile"), dir.join(".nix-profile")).unwrap();
+
+        let mut chain = symlink_chain(&dir.join(".nix-profile"));
+        assert_eq!(
+            chain.next().unwrap().unwrap(),
+            (dir.join(".nix-profile"), dir.join("profile"))
+        );
+        assert_eq!(
+            chain.next().unwrap().unwrap(),
+            (dir.join("profile"), dir.join("profile-7-link"))
+        );
+        assert!(chain.next().unwrap().is_err());
+        assert!(chain.next().is_none());
+
+        symlink("cycle", dir.join("cycle")).unwrap();
+        let chain = symlink_chain(&dir.join("cycle")).collect::<Vec<_>>();
+        assert_eq!(chain.len(), MAX_SYMLINK_HOPS + 1);
+        assert!(chain.last().unwrap().is_err());
+    }
+}
@@ -0,0 +1,395 @@
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fmt::Write as _;
+use std::path::Path;
+use std::process::Command;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread::ThreadId;
+use std::time::Duration;
+use std::time::Instant;
+
+use camino::Utf8PathBuf;
+use miette::Context;
+use miette::IntoDiagnostic;
+use tracing::field::Field;
+use tracing::field::Visit;
+use tracing::span::Attributes;
+use tracing::span::Id;
+use tracing::span::Record;
+use tracing::Level;
+use tracing::Subscriber;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::layer::Context as LayerContext;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// Spans at this level or above (from this crate) are timed.
+const TIMINGS_LEVEL: Level = Level::DEBUG;
+
+/// Labels in the breakdown are truncated to this many characters.
+const MAX_LABEL_WIDTH: usize = 56;
+
+/// Options for collecting and reporting timings.
+#[derive(Debug, Clone, Default)]
+pub struct TimingsOptions {
+    /// Print a per-phase breakdown at the end of the run.
+    pub print: bool,
+    /// Write a Chrome trace-event JSON file here at the end of the run.
+    pub chrome_trace: Option<Utf8PathBuf>,
+}
+
+impl TimingsOptions {
+    pub fn enabled(&self) -> bool {
+        self.print || self.chrome_trace.is_some()
+    }
+}
+
+/// Durations of the spans recorded during a run.
+#[derive(Clone)]
+pub struct Timings {
+    options: TimingsOptions,
+    inner: Arc<Mutex<TimingsInner>>,
+}
+
+struct TimingsInner {
+    /// When the run started.
+    start: Instant,
+    /// The next span index to assign.
+    next_index: usize,
+    /// Small integer IDs for threads, for the Chrome trace.
+    threads: Vec<ThreadId>,
+    /// Spans which have closed, in the order they closed.
+    spans: Vec<SpanRecord>,
+}
+
+/// A closed span.
+#[derive(Debug, Clone)]
+struct SpanRecord {
+    index: usize,
+    parent: Option<usize>,
+    name: &'static str,
+    fields: BTreeMap<String, String>,
+    thread: usize,
+    /// Offset from the start of the run.
+    start: Duration,
+    duration: Duration,
+}
+
+impl SpanRecord {
+    /// A label like `flake_metadata{flake=.}`.
+    fn label(&self) -> String {
+        if self.fields.is_empty() {
+            self.name.to_owned()
+        } else {
+            let fields = self
+                .fields
+                .iter()
+                .map(|(name, value)| format!("{name}={value}"))
+                .collect::<Vec<_>>()
+                .join(" ");
+            format!("{}{{{fields}}}", self.name)
+        }
+    }
+
+    /// Like [`SpanRecord::label`], but truncated to [`MAX_LABEL_WIDTH`].
+    fn short_label(&self) -> String {
+        let label = self.label();
+        if label.chars().count() > MAX_LABEL_WIDTH {
+            let mut label = label.chars().take(MAX_LABEL_WIDTH - 1).collect::<String>();
+            label.push('…');
+            label
+        } else {
+            label
+        }
+    }
+}
+
+impl Timings {
+    pub fn new(options: TimingsOptions) -> Self {
+        Self {
+            options,
+            inner: Arc::new(Mutex::new(TimingsInner {
+                start: Instant::now(),
+                next_index: 0,
+                threads: Vec::new(),
+                spans: Vec::new(),
+            })),
+        }
+    }
+
+    /// A [`Layer`] which records span durations into these [`Timings`].
+    pub fn layer<S>(&self) -> impl Layer<S>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        TimingsLayer {
+            timings: self.clone(),
+        }
+        .with_filter(Targets::new().with_target(env!("CARGO_CRATE_NAME"), TIMINGS_LEVEL))
+    }
+
+    /// Print the breakdown and write the Chrome trace, as requested.
+    pub fn finish(&self) -> miette::Result<()> {
+        let (total, spans) = match self.inner.lock() {
+            Ok(inner) => (inner.start.elapsed(), inner.spans.clone()),
+            Err(_) => {
+                tracing::warn!("Timings lock is poisoned; not reporting timings");
+                return Ok(());
+            }
+        };
+
+        if self.options.print {
+            tracing::info!("Timings:\n{}", format_breakdown(total, &spans));
+        }
+
+        if let Some(path) = &self.options.chrome_trace {
+            let trace = chrome_trace(&spans);
+            std::fs::write(path, serde_json::to_string(&trace).into_diagnostic()?)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to write Chrome trace to {path}"))?;
+            tracing::info!("Wrote Chrome trace to {path}");
+        }
+
+        Ok(())
+    }
+}
+
+/// Format a tree of spans, with sibling spans of the same name and fields combined.
+fn format_breakdown(total: Duration, spans: &[SpanRecord]) -> String {
+    let mut children: BTreeMap<Option<usize>, Vec<&SpanRecord>> = BTreeMap::new();
+    for span in spans {
+        children.entry(span.parent).or_default().push(span);
+    }
+    for siblings in children.values_mut() {
+        siblings.sort_by_key(|span| span.start);
+    }
+
+    let mut ret = format!("{:>9}  {:>4}  total\n", format_duration(total), "100%");
+    format_children(&mut ret, total, &children, None, 0);
+    ret
+}
+
+fn format_children(
+    ret: &mut String,
+    total: Duration,
+    children: &BTreeMap<Option<usize>, Vec<&SpanRecord>>,
+    parent: Option<usize>,
+    depth: usize,
+) {
+    let siblings = match children.get(&parent) {
+        Some(siblings) => siblings,
+        None => return,
+    };
+
+    // Group siblings by label, in order of first appearance.
+    let mut groups: Vec<(String, Vec<&SpanRecord>)> = Vec::new();
+    for span in siblings {
+        let label = span.short_label();
+        match groups.iter_mut().find(|(other, _)| *other == label) {
+            Some((_, spans)) => spans.push(span),
+            None => groups.push((label, vec![span])),
+        }
+    }
+
+    for (label, spans) in groups {
+        let duration = spans.iter().map(|span| span.duration).sum::<Duration>();
+        let percent = if total.is_zero() {
+            0.0
+        } else {
+            100.0 * duration.as_secs_f64() / total.as_secs_f64()
+        };
+        let count = if spans.len() > 1 {
+            format!(" ×{}", spans.len())
+        } else {
+            String::new()
+        };
+        let _ = writeln!(
+            ret,
+            "{:>9}  {:>3.0}%  {:indent$}{label}{count}",
+            format_duration(duration),
+            percent,
+            "",
+            indent = (depth + 1) * 2,
+        );
+        for span in spans {
+            format_children(ret, total, children, Some(span.index), depth + 1);
+        }
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    if duration >= Duration::from_secs(1) {
+        format!("{:.2}s", duration.as_secs_f64())
+    } else {
+        format!("{:.1}ms", duration.as_secs_f64() * 1000.0)
+    }
+}
+
+/// Build a trace in the Chrome trace-event format, viewable in `chrome://tracing` or
+/// <https://ui.perfetto.dev/>.
+///
+/// See: <https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU>
+fn chrome_trace(spans: &[SpanRecord]) -> serde_json::Value {
+    let pid = std::process::id();
+    let events = spans
+        .iter()
+        .map(|span| {
+            serde_json::json!({
+                "name": span.name,
+                "cat": env!("CARGO_PKG_NAME"),
+                "ph": "X",
+                "ts": span.start.as_micros() as u64,
+                "dur": span.duration.as_micros() as u64,
+                "pid": pid,
+                "tid": span.thread,
+                "args": span.fields,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    serde_json::json!({
+        "traceEvents": events,
+        "displayTimeUnit": "ms",
+    })
+}
+
+/// Timing data attached to each open span.
+struct SpanTiming {
+    index: usize,
+    parent: Option<usize>,
+    fields: BTreeMap<String, String>,
+    start: Instant,
+}
+
+struct TimingsLayer {
+    timings: Timings,
+}
+
+impl<S> Layer<S> for TimingsLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: LayerContext<'_, S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+        let index = match self.timings.inner.lock() {
+            Ok(mut inner) => {
+                inner.next_index += 1;
+                inner.next_index
+            }
+            Err(_) => return,
+        };
+        let parent = span
+            .parent()
+            .and_then(|parent| parent.extensions().get::<SpanTiming>().map(|t| t.index));
+        let mut fields = FieldVisitor::default();
+        attrs.record(&mut fields);
+
+        span.extensions_mut().insert(SpanTiming {
+            index,
+            parent,
+            fields: fields.0,
+            start: Instant::now(),
+        });
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: LayerContext<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(timing) = span.extensions_mut().get_mut::<SpanTiming>() {
+                let mut fields = FieldVisitor(std::mem::take(&mut timing.fields));
+                values.record(&mut fields);
+                timing.fields = fields.0;
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: LayerContext<'_, S>) {
+        let span = match ctx.span(&id) {
+            Some(span) => span,
+            None => return,
+        };
+        let timing = match span.extensions_mut().remove::<SpanTiming>() {
+            Some(timing) => timing,
+            None => return,
+        };
+        let duration = timing.start.elapsed();
+
+        if let Ok(mut inner) = self.timings.inner.lock() {
+            let thread_id = std::thread::current().id();
+            let thread = match inner.threads.iter().position(|id| *id == thread_id) {
+                Some(thread) => thread,
+                None => {
+                    inner.threads.push(thread_id);
+                    inner.threads.len() - 1
+                }
+            };
+            let start = timing.start.saturating_duration_since(inner.start);
+            inner.spans.push(SpanRecord {
+                index: timing.index,
+                parent: timing.parent,
+                name: span.name(),
+                fields: timing.fields,
+                thread,
+                start,
+                duration,
+            });
+        }
+    }
+}
+
+#[derive(Default)]
+struct FieldVisitor(BTreeMap<String, String>);
+
+impl Visit for FieldVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_owned(), value.to_owned());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0.insert(field.name().to_owned(), format!("{value:?}"));
+    }
+}
+
+/// Run a subprocess in a `subprocess` span, so that it's included in timings.
+pub trait CommandSpanExt {
+    fn in_span<T>(&mut self, run: impl FnOnce(&mut Self) -> T) -> T;
+}
+
+impl CommandSpanExt for Command {
+    fn in_span<T>(&mut self, run: impl FnOnce(&mut Self) -> T) -> T {
+        let command = display_command(self);
+        let span = tracing::debug_span!("subprocess", %command);
+        let _guard = span.enter();
+        run(self)
+    }
+}
+
+/// Format a command for display, like `nix build --no-link /path/to/flake#attr`.
+///
+/// This uses the program's file name, because `nix` is usually a long store path, and skips
+/// the `--extra-experimental-features` we pass to every `nix` command.
+pub fn display_command(command: &Command) -> String {
+    let program = Path::new(command.get_program());
+    let program = program.file_name().unwrap_or(program.as_os_str());
+    shell_words::join(
+        std::iter::once(program)
+            .chain(display_args(command))
+            .map(|arg| arg.to_string_lossy()),
+    )
+}
+
+/// The command's arguments, without the `--extra-experimental-features` we pass to every `nix`
+/// command.
+pub fn display_args(command: &Command) -> impl Iterator<Item = &OsStr> {
+    let mut args = command.get_args().peekable();
+    if args
+        .peek()
+        .is_some_and(|arg| *arg == "--extra-experimental-features")
+    {
+        args.nth(1);
+    }
+    args
+}
@@ -1,4 +1,14 @@
+use std::io::Write;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use camino::Utf8Path;
+use miette::miette;
+use miette::Context;
 use miette::IntoDiagnostic;
+use tracing_appender::rolling::RollingFileAppender;
+use tracing_appender::rolling::Rotation;
+use tracing_subscriber::fmt::format::FmtSpan;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::reload::Handle;
 use tracing_subscriber::util::SubscriberInitExt;
@@ -6,34 +16,149 @@ use tracing_subscriber::EnvFilter;
 use tracing_subscriber::Layer;
 use tracing_subscriber::Registry;
 
+use crate::timings::Timings;
+
 type ReloadHandle = Handle<EnvFilter, Registry>;
 
 /// The default filter directive.
 pub const DEFAULT_FILTER: &str = "info";
 
+/// The filter directive for the log file.
+const LOG_FILE_FILTER: &str = "debug";
+
+/// How many rotated log files to keep.
+const MAX_LOG_FILES: usize = 14;
+
+/// The format to write logs to the terminal in.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default)]
+pub enum LogFormat {
+    /// Human-readable, colorful output.
+    #[default]
+    Human,
+    /// Newline-delimited JSON objects.
+    Json,
+}
+
+/// Handles for reconfiguring logging after the configuration is loaded.
+pub struct TracingHandles {
+    filter: ReloadHandle,
+    log_file_filter: ReloadHandle,
+    log_file: LogFileWriter,
+}
+
 pub fn install_tracing(
     filter_directives: &str,
-) -> std::result::Result<ReloadHandle, miette::Report> {
+    format: LogFormat,
+    timings: Option<&Timings>,
+) -> std::result::Result<TracingHandles, miette::Report> {
     let env_filter = EnvFilter::try_new(filter_directives).into_diagnostic()?;
-
     let (env_filter, reload_handle) = tracing_subscriber::reload::Layer::new(env_filter);
 
-    let subscriber = tracing_human_layer::HumanLayer::default().with_filter(env_filter);
+    let terminal = match format {
+        LogFormat::Human => tracing_human_layer::HumanLayer::default().boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .with_span_events(FmtSpan::CLOSE)
+            .with_writer(std::io::stderr)
+            .boxed(),
+    }
+    .with_filter(env_filter);
+
+    // The log file is disabled until we've loaded the configuration.
+    let log_file = LogFileWriter::default();
+    let (log_file_filter, log_file_reload_handle) =
+        tracing_subscriber::reload::Layer::new(EnvFilter::new("off"));
+    let log_file_layer = tracing_subscriber::fmt::layer()
+        .json()
+        .with_span_events(FmtSpan::CLOSE)
+        .with_writer({
+            let log_file = log_file.clone();
+            move || log_file.clone()
+        })
+        .with_filter(log_file_filter);
+
+    let mut layers = vec![terminal.boxed(), log_file_layer.boxed()];
+    if let Some(timings) = timings {
+        layers.push(timings.layer().boxed());
+    }
 
     tracing_subscriber::registry()
-        .with(subscriber)
+        .with(layers)
         .try_init()
         .into_diagnostic()?;
 
-    Ok(reload_handle)
+    Ok(TracingHandles {
+        filter: reload_handle,
+        log_file_filter: log_file_reload_handle,
+        log_file,
+    })
 }
 
-pub fn update_log_filters(handle: &ReloadHandle, filter_directives: &str) -> miette::Result<()> {
-    let env_filter = EnvFilter::try_new(filter_directives).into_diagnostic()?;
+impl TracingHandles {
+    pub fn update_log_filters(&self, filter_directives: &str) -> miette::Result<()> {
+        let env_filter = EnvFilter::try_new(filter_directives).into_diagnostic()?;
 
-    handle
-        .modify(|old_filter| {
-            *old_filter = env_filter;
-        })
-        .into_diagnostic()
+        self.filter
+            .modify(|old_filter| {
+                *old_filter = env_filter;
+            })
+            .into_diagnostic()
+    }
+
+    /// Start writing JSON logs to a daily-rotated file in `directory`.
+    pub fn enable_log_file(&self, directory: &Utf8Path) -> miette::Result<()> {
+        std::fs::create_dir_all(directory)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to create directory {directory}"))?;
+
+        let appender = RollingFileAppender::builder()
+            .rotation(Rotation::DAILY)
+            .filename_prefix("home-mangler")
+            .filename_suffix("log")
+            .max_log_files(MAX_LOG_FILES)
+            .build(directory)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to open log file in {directory}"))?;
+
+        *self
+            .log_file
+            .0
+            .lock()
+            .map_err(|_| miette!("Log file lock is poisoned"))? = Some(appender);
+
+        self.log_file_filter
+            .modify(|filter| {
+                *filter = EnvFilter::new(LOG_FILE_FILTER);
+            })
+            .into_diagnostic()?;
+
+        tracing::debug!(%directory, "Writing logs to file");
+        Ok(())
+    }
+}
+
+/// A writer for the log file, which discards output until a file is opened.
+#[derive(Clone, Default)]
+struct LogFileWriter(Arc<Mutex<Option<RollingFileAppender>>>);
+
+impl Write for LogFileWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self.0.lock() {
+            Ok(mut appender) => match appender.as_mut() {
+                Some(appender) => appender.write(buf),
+                None => Ok(buf.len()),
+            },
+            Err(_) => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self.0.lock() {
+            Ok(mut appender) => match appender.as_mut() {
+                Some(appender) => appender.flush(),
+                None => Ok(()),
+            },
+            Err(_) => Ok(()),
+        }
+    }
 }
@@ -0,0 +1,117 @@
+use camino::Utf8Path;
+use miette::miette;
+use miette::Context;
+
+use crate::flake::Flake;
+use crate::flake_lock::format_input_changes;
+use crate::flake_lock::InputChange;
+use crate::git::GitCheckout;
+use crate::nix::Nix;
+
+/// How to update the flake's inputs.
+#[derive(Debug, Clone, Default)]
+pub struct UpdateOptions {
+    /// The inputs to update. If empty, all inputs are updated.
+    pub inputs: Vec<String>,
+    /// Commit `flake.lock` after updating it.
+    pub auto_commit_lock: bool,
+}
+
+/// Update the flake's inputs and return the inputs that changed.
+#[tracing::instrument(level = "debug", skip_all)]
+pub fn update_flake(
+    nix: &Nix,
+    flake: &Flake,
+    options: &UpdateOptions,
+) -> miette::Result<Vec<InputChange>> {
+    let lock_file = Utf8Path::new("flake.lock");
+    let checkout = if options.auto_commit_lock {
+        lock_checkout(flake, lock_file)?
+    } else {
+        None
+    };
+
+    let input_changes = nix
+        .flake_update(flake, &options.inputs)
+        .wrap_err_with(|| format!("Failed to update `flake.lock` for {flake}"))?;
+
+    if input_changes.is_empty() {
+        tracing::info!("Flake inputs are already up to date");
+    } else {
+        tracing::info!(
+            "Updated flake inputs:\n{}",
+            format_input_changes(&input_changes)
+        );
+    }
+
+    if let Some(checkout) = checkout {
+        if checkout.is_dirty(lock_file)? {
+            tracing::info!("Committing `flake.lock`");
+            checkout
+                .commit_file(lock_file, &commit_message(&input_changes))
+                .wrap_err("Failed to commit `flake.lock`")?;
+        } else {
+            tracing::debug!("`flake.lock` is unchanged or ignored; not committing");
+        }
+    }
+
+    Ok(input_changes)
+}
+
+/// Find the Git checkout to commit `flake.lock` in, and check that it doesn't already have
+/// uncommitted changes.
+fn lock_checkout(flake: &Flake, lock_file: &Utf8Path) -> miette::Result<Option<GitCheckout>> {
+    let path = match flake {
+        Flake::Path(flake) => flake.path(),
+        Flake::Url(_) => {
+            tracing::warn!(
+                "Not committing `flake.lock` because {flake} is not a local path; set `flake` to a path to use `auto-commit-lock`"
+            );
+            return Ok(None);
+        }
+    };
+
+    let checkout = match GitCheckout::find(path)? {
+        Some(checkout) => checkout,
+        None => {
+            tracing::warn!("Not committing `flake.lock` because {path} is not a Git checkout");
+            return Ok(None);
+        }
+    };
+
+    if checkout.is_dirty(lock_file)? {
+        return Err(miette!(
+            "`{path}/flake.lock` has uncommitted changes; refusing to update and commit it.\nCommit or discard the changes, or disable `auto-commit-lock`."
+        ));
+    }
+
+    Ok(Some(checkout))
+}
+
+fn commit_message(input_changes: &[InputChange]) -> String {
+    let subject = match input_changes {
+        [] => "flake.lock: Update".to_owned(),
+        [change] => format!("flake.lock: Update {}", change.input),
+        _ => format!("flake.lock: Update {} inputs", input_changes.len()),
+    };
+
+    let mut message = subject;
+    if !input_changes.is_empty() {
+        message.push_str("\n\n");
+        for change in input_changes {
+            message.push_str(&format!("• {}: ", change.input));
+            match (&change.old, &change.new) {
+                (Some(old), Some(new)) => message.push_str(&format!("{old} → {new}")),
+                (None, Some(new)) => message.push_str(&format!("added at {new}")),
+                (Some(old), None) => message.push_str(&format!("removed (was {old})")),
+                (None, None) => {}
+            }
+            message.push('\n');
+            if let Some(url) = &change.compare_url {
+                message.push_str(&format!("  {url}\n"));
+            }
+        }
+    }
+
+    message
+}